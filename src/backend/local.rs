@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    fs::{create_dir_all, remove_file, rename, File},
+    io::{copy, AsyncReadExt, AsyncSeekExt, AsyncWrite},
+};
+use torut::onion::OnionAddressV3;
+
+use crate::{
+    backend::{BackendFuture, EncodedFile, StorageBackend},
+    config::{get_cfg, get_storage_path, place_storage_path},
+    hash::{blocking, extract_slice},
+};
+
+/// ## Local volumes
+/// Encoded files are placed on the configured volumes.
+/// A storage provider keeps each client's files in their own directory, so clients can't read or overwrite each other's.
+#[derive(Default)]
+pub struct LocalBackend {
+    client: Option<OnionAddressV3>,
+    staged: HashMap<PathBuf, (PathBuf, u64)>, // Volume and size of each file staged but not yet recorded
+}
+
+impl LocalBackend {
+    pub fn for_client(client: OnionAddressV3) -> Self {
+        Self {
+            client: Some(client),
+            ..Default::default()
+        }
+    }
+
+    fn dir(&self, volume: &Path) -> PathBuf {
+        match &self.client {
+            Some(client) => volume.join(client.get_address_without_dot_onion()),
+            None => volume.to_path_buf(),
+        }
+    }
+
+    /// Path to a stored encoded file
+    pub async fn locate(&self, blake3_hash: &str) -> Result<PathBuf> {
+        if self.client.is_none() {
+            return Ok(get_storage_path(blake3_hash).await?.join(blake3_hash));
+        }
+
+        get_cfg()
+            .await?
+            .volumes
+            .iter()
+            .map(|vol| self.dir(&vol.path).join(blake3_hash))
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow!("Encoded file {} not found on any volume", blake3_hash))
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    /// Room is set aside for a staged file on the volume it's placed on until it's unstaged,
+    /// since volume usage only counts files once they're recorded, and several may be encoded at once.
+    fn stage<'a>(&'a mut self, blake3_hash: &'a str, size: u64) -> BackendFuture<'a, PathBuf> {
+        Box::pin(async move {
+            let mut reserved: HashMap<PathBuf, u64> = HashMap::new();
+            for (volume, size) in self.staged.values() {
+                *reserved.entry(volume.clone()).or_default() += size;
+            }
+
+            let volume = place_storage_path(blake3_hash, size, &reserved).await?;
+            let dir = self.dir(&volume);
+            create_dir_all(&dir).await?;
+
+            let staged = dir.join(blake3_hash);
+            self.staged.insert(staged.clone(), (volume, size));
+            Ok(staged)
+        })
+    }
+
+    fn unstage(&mut self, staged: &Path) {
+        self.staged.remove(staged);
+    }
+
+    /// Files are encoded in place. Those staged under a temporary name are renamed to their hash, on the volume they were staged on.
+    fn put<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        staged: &'a Path,
+    ) -> BackendFuture<'a, Option<PathBuf>> {
+        Box::pin(async move {
+            let stored = if staged.ends_with(blake3_hash) {
+                staged.to_path_buf()
+            } else {
+                let stored = staged.with_file_name(blake3_hash);
+                rename(staged, &stored).await?;
+                stored
+            };
+
+            Ok(get_cfg()
+                .await?
+                .volumes
+                .into_iter()
+                .map(|vol| vol.path)
+                .find(|volume| stored.starts_with(volume)))
+        })
+    }
+
+    fn stat<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, u64> {
+        Box::pin(async move {
+            let path = self.locate(blake3_hash).await?;
+            Ok(File::open(path).await?.metadata().await?.len())
+        })
+    }
+
+    fn get_range<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        offset: u64,
+        len: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BackendFuture<'a, u64> {
+        Box::pin(async move {
+            let mut file = File::open(self.locate(blake3_hash).await?).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let len = if len == 0 { u64::MAX } else { len };
+            Ok(copy(&mut file.take(len), out).await?)
+        })
+    }
+
+    fn extract_slice<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        slice_index: u64,
+    ) -> BackendFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let path = self.locate(blake3_hash).await?;
+            blocking(move || extract_slice(&path, slice_index)).await
+        })
+    }
+
+    fn delete<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            remove_file(self.locate(blake3_hash).await?).await?;
+            Ok(())
+        })
+    }
+
+    /// Files on this node are read where they are, instead of being copied
+    fn fetch<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, EncodedFile> {
+        Box::pin(async move {
+            Ok(EncodedFile {
+                path: self.locate(blake3_hash).await?,
+                temporary: false,
+            })
+        })
+    }
+}
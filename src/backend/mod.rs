@@ -0,0 +1,174 @@
+//! # Storage backends
+//! Encoded files are kept either on this node's volumes or with a storage provider.
+//! Everything that stores, reads, verifies or deletes them goes through a `StorageBackend`.
+use std::{
+    env::temp_dir,
+    fs::remove_file,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use anyhow::Result;
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt},
+};
+use torut::onion::OnionAddressV3;
+
+use crate::net::channel::connect_opened;
+
+pub mod local;
+pub mod remote;
+
+use local::LocalBackend;
+use remote::RemoteBackend;
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// An encoded file that can be read locally. Temporary copies fetched from a provider are removed once dropped.
+pub struct EncodedFile {
+    pub path: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for EncodedFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+/// A file encoded where it was staged. It's removed once dropped, unless it was stored.
+pub struct Staged {
+    path: PathBuf,
+    stored: bool,
+}
+
+impl Staged {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            stored: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the file, once it was put where it's stored
+    pub fn stored(mut self) {
+        self.stored = true;
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.stored {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+pub trait StorageBackend: Send {
+    /// Path a file should be encoded to before it's put, with room for `size` encoded bytes
+    fn stage<'a>(&'a mut self, blake3_hash: &'a str, size: u64) -> BackendFuture<'a, PathBuf>;
+
+    /// Releases the room set aside for a staged file, once it was recorded or discarded
+    fn unstage(&mut self, _staged: &Path) {}
+
+    /// Stores a staged encoded file. Returns the volume it was placed on, if it's kept on this node.
+    fn put<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        staged: &'a Path,
+    ) -> BackendFuture<'a, Option<PathBuf>>;
+
+    /// Length of a stored encoded file
+    fn stat<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, u64>;
+
+    /// Writes `len` bytes of an encoded file from `offset` to `out`, or everything after `offset` if `len` is 0
+    fn get_range<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        offset: u64,
+        len: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BackendFuture<'a, u64>;
+
+    /// Extracts a bao slice from an encoded file, to be checked against its bao hash
+    fn extract_slice<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        slice_index: u64,
+    ) -> BackendFuture<'a, Vec<u8>>;
+
+    fn delete<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, ()>;
+
+    /// Storage provider files are kept with, if they aren't kept on this node
+    fn peer(&self) -> Option<OnionAddressV3> {
+        None
+    }
+
+    /// An encoded file that can be read locally, fetched into a temporary file unless it's already on this node
+    fn fetch<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, EncodedFile> {
+        Box::pin(async move {
+            let encoded = EncodedFile {
+                path: temp_dir().join(format!("forage-{}", blake3_hash)),
+                temporary: true,
+            };
+
+            let mut file = File::create(&encoded.path).await?;
+            self.get_range(blake3_hash, 0, 0, &mut file).await?;
+            file.flush().await?;
+
+            Ok(encoded)
+        })
+    }
+}
+
+/// ## Storage
+/// New files go to the storage provider if a channel was opened to one, otherwise to this node's volumes.
+/// Files stored on this node before a channel was opened stay where they are.
+pub struct Storage {
+    local: LocalBackend,
+    remote: Option<RemoteBackend>,
+}
+
+impl Storage {
+    pub async fn open() -> Result<Self> {
+        Ok(Self {
+            local: LocalBackend::default(),
+            remote: connect_opened().await?.map(RemoteBackend::new),
+        })
+    }
+
+    /// Storage provider files are sent to, if a channel was opened to one
+    pub fn provider(&self) -> Option<OnionAddressV3> {
+        self.remote.as_ref().map(RemoteBackend::provider)
+    }
+
+    pub fn for_new(&mut self) -> &mut dyn StorageBackend {
+        match &mut self.remote {
+            Some(remote) => remote,
+            None => &mut self.local,
+        }
+    }
+
+    /// Backend a stored file is kept on, given the volume it was recorded on
+    pub async fn for_file(
+        &mut self,
+        blake3_hash: &str,
+        volume: Option<&Path>,
+    ) -> &mut dyn StorageBackend {
+        // Files encoded before volumes were recorded also have no volume, but can still be found locally
+        let local = volume.is_some() || self.local.locate(blake3_hash).await.is_ok();
+
+        match &mut self.remote {
+            Some(remote) if !local => remote,
+            _ => &mut self.local,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    env::temp_dir,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::remove_file,
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use torut::onion::OnionAddressV3;
+
+use crate::{
+    backend::{BackendFuture, StorageBackend},
+    hash::{blocking, extract_slice},
+    net::{channel::Channel, protocol::SLICE_PROOFS},
+};
+
+/// ## Storage provider
+/// Encoded files are staged in a temporary directory, then sent over a storage channel
+pub struct RemoteBackend<S = TcpStream> {
+    channel: Channel<S>,
+}
+
+impl<S> RemoteBackend<S> {
+    pub fn new(channel: Channel<S>) -> Self {
+        Self { channel }
+    }
+
+    pub fn provider(&self) -> OnionAddressV3 {
+        self.channel.provider
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> StorageBackend for RemoteBackend<S> {
+    fn stage<'a>(&'a mut self, blake3_hash: &'a str, _size: u64) -> BackendFuture<'a, PathBuf> {
+        Box::pin(async move { Ok(temp_dir().join(format!("forage-{}", blake3_hash))) })
+    }
+
+    fn put<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        staged: &'a Path,
+    ) -> BackendFuture<'a, Option<PathBuf>> {
+        Box::pin(async move {
+            self.channel.put(blake3_hash, staged).await?;
+            remove_file(staged).await?;
+            Ok(None)
+        })
+    }
+
+    fn stat<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, u64> {
+        Box::pin(self.channel.stat(blake3_hash))
+    }
+
+    fn get_range<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        offset: u64,
+        len: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BackendFuture<'a, u64> {
+        Box::pin(self.channel.get_range(blake3_hash, offset, len, out))
+    }
+
+    fn extract_slice<'a>(
+        &'a mut self,
+        blake3_hash: &'a str,
+        slice_index: u64,
+    ) -> BackendFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            if self.channel.capabilities & SLICE_PROOFS != 0 {
+                return self.channel.slice_proof(blake3_hash, slice_index).await;
+            }
+
+            // Providers that can't answer slice challenges still have to send the whole file
+            let encoded = self.fetch(blake3_hash).await?;
+            blocking(move || extract_slice(&encoded.path, slice_index)).await
+        })
+    }
+
+    fn delete<'a>(&'a mut self, blake3_hash: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(self.channel.delete(blake3_hash))
+    }
+
+    fn peer(&self) -> Option<OnionAddressV3> {
+        Some(self.channel.provider)
+    }
+}
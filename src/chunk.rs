@@ -0,0 +1,132 @@
+//! # Content-defined chunking
+//! Large files are split where their contents say to, FastCDC-style, rather than at fixed offsets.
+//! An edit only moves the boundaries of the chunks around it, so the rest of the file is deduplicated against what was already stored.
+use std::io::{ErrorKind, Read, Result};
+
+use once_cell::sync::Lazy;
+
+use crate::config::ChunkingCfg;
+
+/// Smallest chunk size allowed, however small chunk sizes are configured
+const MIN_CHUNK_LEN: usize = 64;
+
+/// Gear hash table, generated with splitmix64 from a fixed seed.
+/// Chunk boundaries depend on it, so it must never change, or chunks stored before won't be found again.
+static GEAR: Lazy<[u64; 256]> = Lazy::new(|| {
+    let mut state: u64 = 0x466f_7261_6765; // "Forage"
+    let mut table = [0; 256];
+
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *entry = z ^ (z >> 31);
+    }
+
+    table
+});
+
+/// Mask of the top `bits` bits of the gear hash, which depend on the most recently hashed bytes
+fn mask(bits: u32) -> u64 {
+    !0 << (64 - bits.clamp(1, 63))
+}
+
+/// Splits what's read from a reader into chunks of `min` to `max` bytes, cut where the gear hash matches a mask
+pub struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_small: u64, // Harder to match, used until a chunk reaches the average size
+    mask_large: u64, // Easier to match, used after it, so chunk sizes cluster around the average
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R, cfg: &ChunkingCfg) -> Self {
+        let kib = |kib: u64| usize::try_from(kib.saturating_mul(1024)).unwrap_or(usize::MAX);
+        let min = kib(cfg.min_kib).max(MIN_CHUNK_LEN);
+        let max = kib(cfg.max_kib).max(min);
+        let avg = kib(cfg.avg_kib).clamp(min, max);
+        let bits = avg.ilog2();
+
+        Self {
+            reader,
+            buf: Vec::with_capacity(max),
+            eof: false,
+            min,
+            avg,
+            max,
+            mask_small: mask(bits + 1),
+            mask_large: mask(bits - 1),
+        }
+    }
+
+    /// Reads until a whole chunk of the largest size is buffered, or the reader runs out
+    fn fill(&mut self) -> Result<()> {
+        while !self.eof && self.buf.len() < self.max {
+            let len = self.buf.len();
+            self.buf.resize(self.max, 0);
+
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.buf.truncate(len);
+                    self.eof = true;
+                }
+                Ok(read) => self.buf.truncate(len + read),
+                Err(e) if e.kind() == ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Length of the next chunk in the buffer. Bytes before the smallest chunk size aren't hashed, since no cut can fall there.
+    fn cut_point(&self) -> usize {
+        let len = self.buf.len().min(self.max);
+        if len <= self.min {
+            return len;
+        }
+
+        let normal = self.avg.min(len);
+        let mut hash = 0u64;
+
+        for (i, byte) in self.buf[..len].iter().enumerate().skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < normal {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        len
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let cut = self.cut_point();
+        Some(Ok(self.buf.drain(..cut).collect()))
+    }
+}
@@ -1,13 +1,16 @@
-use std::{env, path::PathBuf};
+use std::{
+    cmp::Reverse, collections::HashMap, env, fmt, io::ErrorKind, net::SocketAddr, path::PathBuf,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Utc};
 use directories_next::{BaseDirs, UserDirs};
+use human_bytes::human_bytes;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    fs::{create_dir_all, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+use tokio::fs::{create_dir_all, read, remove_file, rename, write};
+
+use crate::db::{get_volume, get_volume_usage, FileInfo};
 
 pub struct EnvCfg {
     pub usr_home_dir: PathBuf,
@@ -36,21 +39,308 @@ fn init_env_cfg() -> Result<EnvCfg> {
 
 pub static ENV_CFG: Lazy<EnvCfg> = Lazy::new(|| init_env_cfg().unwrap());
 
-#[derive(Serialize, Deserialize)]
+pub const MEGABYTE: u64 = 1024 * 1024;
+
+/// Allocation of the volume used when none are configured, in megabytes (10 GiB)
+const DEFAULT_VOLUME_ALLOCATED: u64 = 10 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Volume {
-    path: PathBuf,  // Path to mounted volume
-    allocated: u64, // Allocated capacity in megabytes
+    pub path: PathBuf,  // Path to mounted volume
+    pub allocated: u64, // Allocated capacity in megabytes
+}
+
+impl Volume {
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocated.saturating_mul(MEGABYTE)
+    }
+}
+
+/// How encoded files are spread across multiple volumes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// Place each file on the volume with the most unused allocation
+    #[default]
+    FreeSpace,
+    /// Place each file on a volume chosen by its hash (rendezvous hashing), so placement is stable as volumes are added
+    HashShard,
+}
+
+/// Optional zstd compression of file contents before they're encoded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CompressionCfg {
+    pub enabled: bool,
+    pub level: i32,
+    /// Mime types (or prefixes, such as `video/`) that are already compressed, and stored as-is
+    pub skip_mime_types: Vec<String>,
+}
+
+impl Default for CompressionCfg {
+    fn default() -> Self {
+        let skip_mime_types = [
+            "image/jpeg",
+            "image/png",
+            "image/gif",
+            "image/webp",
+            "image/heif",
+            "image/avif",
+            "video/",
+            "audio/",
+            "application/zip",
+            "application/gzip",
+            "application/x-bzip2",
+            "application/x-xz",
+            "application/zstd",
+            "application/x-7z-compressed",
+            "application/vnd.rar",
+            "application/x-rar-compressed",
+            "application/epub+zip",
+        ];
+
+        Self {
+            enabled: false,
+            level: 3,
+            skip_mime_types: skip_mime_types.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+impl CompressionCfg {
+    /// Whether a file of this mime type should be compressed
+    pub fn compresses(&self, mime_type: &str) -> bool {
+        self.enabled
+            && !self
+                .skip_mime_types
+                .iter()
+                .any(|skip| mime_type.starts_with(skip.as_str()))
+    }
+}
+
+/// Local Tor daemon used to publish this node's hidden service, and to reach storage providers' hidden services
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TorCfg {
+    pub control_address: SocketAddr,
+    /// Only needed if the control port uses password authentication, otherwise cookie authentication is used
+    pub control_password: Option<String>,
+    /// Port on the onion address, and on localhost where the node listens for connections from it
+    pub service_port: u16,
+    pub socks_address: SocketAddr,
+}
+
+impl Default for TorCfg {
+    fn default() -> Self {
+        Self {
+            control_address: SocketAddr::from(([127, 0, 0, 1], 9051)),
+            control_password: None,
+            service_port: 9741,
+            socks_address: SocketAddr::from(([127, 0, 0, 1], 9050)),
+        }
+    }
+}
+
+/// How much stored data each verification round challenges
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct VerificationCfg {
+    /// Files checked each round: the oldest, the newest, and the rest chosen at random
+    pub files: usize,
+    pub slices_per_file: usize,
+    /// Failures in a row, from a storage provider or this node's volumes, before they're reported as a problem
+    pub alert_after_failures: u64,
+    /// How often the running node verifies stored data. Set to 0 to only verify with `forage verify`.
+    pub interval_minutes: u64,
+    /// Up to this much longer is waited between rounds, at random, so providers can't predict them
+    pub jitter_minutes: u64,
+    /// Most slices a storage provider is asked for in any 24 hours by the running node
+    pub daily_challenges_per_provider: u64,
+    /// Longest wait between rounds, as the interval doubles after each failed round
+    pub max_backoff_minutes: u64,
+}
+
+impl Default for VerificationCfg {
+    fn default() -> Self {
+        Self {
+            files: 5,
+            slices_per_file: 3,
+            alert_after_failures: 3,
+            interval_minutes: 60,
+            jitter_minutes: 10,
+            daily_challenges_per_provider: 1000,
+            max_backoff_minutes: 24 * 60,
+        }
+    }
+}
+
+/// How uploads are spread across threads
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct UploadCfg {
+    /// Files hashed and encoded at once. Set to 0 to use one worker per CPU core.
+    pub workers: usize,
+    /// Uploaded files recorded in each database transaction
+    pub batch_size: usize,
+}
+
+impl Default for UploadCfg {
+    fn default() -> Self {
+        Self {
+            workers: 0,
+            batch_size: 100,
+        }
+    }
+}
+
+impl UploadCfg {
+    /// Number of workers to start, resolving 0 to the number of CPU cores.
+    /// Without the `rayon` feature, files are hashed and encoded one at a time.
+    pub fn worker_count(&self) -> usize {
+        if !cfg!(feature = "rayon") {
+            return 1;
+        }
+
+        match self.workers {
+            0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            workers => workers,
+        }
+    }
+}
+
+/// Optional content-defined chunking, so files that change in place only upload the parts that changed.
+/// Files larger than the minimum chunk size are split into chunks that are deduplicated across every file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChunkingCfg {
+    pub enabled: bool,
+    /// Smallest chunk, in kibibytes. Changing chunk sizes moves chunk boundaries, so chunks stored before won't be reused.
+    pub min_kib: u64,
+    /// Chunk size aimed for, in kibibytes. Rounded down to a power of two.
+    pub avg_kib: u64,
+    /// Largest chunk, in kibibytes
+    pub max_kib: u64,
+}
+
+impl Default for ChunkingCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_kib: 256,
+            avg_kib: 1024,
+            max_kib: 4096,
+        }
+    }
+}
+
+impl ChunkingCfg {
+    /// Whether a file of `size` bytes should be split into chunks
+    pub fn chunks(&self, size: u64) -> bool {
+        self.enabled && size > self.min_kib * 1024
+    }
+}
+
+/// Calendar period a date falls in, such as its year and month
+type Period = fn(&DateTime<Utc>) -> (i32, u32);
+
+/// How many older revisions of each file are kept when pruning. Revisions any rule keeps aren't pruned.
+/// Daily, weekly and monthly rules keep the newest revision from each of that many most recent days, weeks or months that have one.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RetentionCfg {
+    pub keep_last: Option<usize>,
+    pub keep_within_days: Option<i64>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+}
+
+impl RetentionCfg {
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some()
+            || self.keep_within_days.is_some()
+            || self.keep_daily.is_some()
+            || self.keep_weekly.is_some()
+            || self.keep_monthly.is_some()
+    }
+
+    /// Revisions of a file that no rule keeps, given newest first. Revisions that haven't been dropped are always kept.
+    pub fn to_prune<'a>(&self, revisions: &'a [FileInfo], now: DateTime<Utc>) -> Vec<&'a FileInfo> {
+        let mut keep = vec![false; revisions.len()];
+
+        for (i, revision) in revisions.iter().enumerate() {
+            keep[i] |= !revision.dropped;
+            keep[i] |= matches!(self.keep_last, Some(n) if i < n);
+            keep[i] |= matches!(self.keep_within_days,
+                Some(days) if revision.date_modified >= now - Duration::days(days));
+        }
+
+        let snapshots: [(Option<usize>, Period); 3] = [
+            (self.keep_daily, |date| (date.year(), date.ordinal())),
+            (self.keep_weekly, |date| {
+                let week = date.iso_week();
+                (week.year(), week.week())
+            }),
+            (self.keep_monthly, |date| (date.year(), date.month())),
+        ];
+
+        for (count, period) in snapshots {
+            let count = match count {
+                Some(count) => count,
+                None => continue,
+            };
+            let mut periods = Vec::with_capacity(count);
+
+            // Newest first, so the first revision seen in each period is the one kept
+            for (i, revision) in revisions.iter().enumerate() {
+                let revision_period = period(&revision.date_modified);
+
+                if !periods.contains(&revision_period) {
+                    if periods.len() == count {
+                        break;
+                    }
+                    periods.push(revision_period);
+                    keep[i] = true;
+                }
+            }
+        }
+
+        revisions
+            .iter()
+            .zip(keep)
+            .filter(|(_, keep)| !keep)
+            .map(|(revision, _)| revision)
+            .collect()
+    }
 }
 
 #[derive(Deserialize)]
 struct SysCfgFile {
     forage_data_dir: Option<String>,
+    placement: Option<Placement>,
+    encrypt: Option<bool>,
+    compression: Option<CompressionCfg>,
+    tor: Option<TorCfg>,
+    retention: Option<RetentionCfg>,
+    verification: Option<VerificationCfg>,
+    upload: Option<UploadCfg>,
+    chunking: Option<ChunkingCfg>,
     volume: Option<Vec<Volume>>,
 }
 
 #[derive(Serialize)]
 pub struct SysCfg {
     pub forage_data_dir: PathBuf,
+    pub placement: Placement,
+    /// Encrypt file contents before they're encoded (Caution! Experimental encryption!)
+    pub encrypt: bool,
+    pub compression: CompressionCfg,
+    pub tor: TorCfg,
+    pub retention: RetentionCfg,
+    pub verification: VerificationCfg,
+    pub upload: UploadCfg,
+    pub chunking: ChunkingCfg,
+    #[serde(rename = "volume")]
     pub volumes: Vec<Volume>,
 }
 
@@ -58,18 +348,12 @@ pub async fn get_cfg() -> Result<SysCfg> {
     create_dir_all(&ENV_CFG.forage_cfg_dir).await?;
     create_dir_all(&ENV_CFG.forage_cfg_dir.join("sqlite_db")).await?;
 
-    let mut cfg_contents = vec![];
-
-    // Creates new empty config file if it doesn't exist
-    let mut cfg_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(&ENV_CFG.forage_cfg_file)
-        .await?;
-
-    cfg_file.read_to_end(&mut cfg_contents).await?;
+    // A missing config file reads as empty, and is written out below with defaults
+    let cfg_contents = match read(&ENV_CFG.forage_cfg_file).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
 
     let sys_cfg: SysCfgFile = toml::from_slice(&cfg_contents)?;
 
@@ -86,7 +370,7 @@ pub async fn get_cfg() -> Result<SysCfg> {
         .unwrap_or_else(|| {
             vec![Volume {
                 path: PathBuf::from("/tmp/forage_data"),
-                allocated: 1,
+                allocated: DEFAULT_VOLUME_ALLOCATED,
             }]
         });
 
@@ -103,24 +387,135 @@ pub async fn get_cfg() -> Result<SysCfg> {
 
     let config = SysCfg {
         forage_data_dir,
+        placement: sys_cfg.placement.unwrap_or_default(),
+        encrypt: sys_cfg.encrypt.unwrap_or(false),
+        compression: sys_cfg.compression.unwrap_or_default(),
+        tor: sys_cfg.tor.unwrap_or_default(),
+        retention: sys_cfg.retention.unwrap_or_default(),
+        verification: sys_cfg.verification.unwrap_or_default(),
+        upload: sys_cfg.upload.unwrap_or_default(),
+        chunking: sys_cfg.chunking.unwrap_or_default(),
         volumes,
     };
 
-    // Write parsed config back out to config file
+    // Write parsed config back out to config file.
+    // It's renamed over from a temporary file, so other processes reading it never see it half-written.
     let toml = toml::to_string_pretty(&config)?;
-    cfg_file.write_all(toml.as_bytes()).await?;
+    if toml.as_bytes() != cfg_contents.as_slice() {
+        let tmp_file = ENV_CFG
+            .forage_cfg_file
+            .with_extension(format!("toml.{:016x}", rand::random::<u64>()));
+
+        let written = async {
+            write(&tmp_file, toml.as_bytes()).await?;
+            rename(&tmp_file, &ENV_CFG.forage_cfg_file).await
+        }
+        .await;
+
+        if written.is_err() {
+            let _ = remove_file(&tmp_file).await;
+        }
+        written?;
+    }
 
     Ok(config)
 }
 
-pub async fn get_storage_path() -> Result<PathBuf> {
+/// Returned when no volume has enough unused allocation left for an encoded file
+#[derive(Debug)]
+pub struct VolumeFull {
+    pub needed: u64,    // Encoded bytes to be written
+    pub available: u64, // Largest unused allocation on any volume
+}
+
+impl VolumeFull {
+    pub fn shortfall(&self) -> u64 {
+        self.needed.saturating_sub(self.available)
+    }
+}
+
+impl fmt::Display for VolumeFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Storage volumes are full: {} needed, {} available ({} short)",
+            human_bytes(self.needed as f64),
+            human_bytes(self.available as f64),
+            human_bytes(self.shortfall() as f64),
+        )
+    }
+}
+
+impl std::error::Error for VolumeFull {}
+
+/// Chooses a volume with room for a newly encoded file of `size` bytes
+pub fn select_volume<'a>(
+    volumes: &'a [Volume],
+    placement: Placement,
+    usage: &HashMap<PathBuf, u64>,
+    blake3_hash: &str,
+    size: u64,
+) -> Result<&'a Volume, VolumeFull> {
+    let free = |vol: &Volume| {
+        let used = usage.get(&vol.path).copied().unwrap_or(0);
+        vol.allocated_bytes().saturating_sub(used)
+    };
+
+    let mut ranked: Vec<&Volume> = volumes.iter().collect();
+
+    match placement {
+        // Stable sort keeps ties in favor of the first configured volume
+        Placement::FreeSpace => ranked.sort_by_key(|vol| Reverse(free(vol))),
+        Placement::HashShard => ranked.sort_by_cached_key(|vol| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(blake3_hash.as_bytes());
+            hasher.update(vol.path.to_string_lossy().as_bytes());
+            Reverse(*hasher.finalize().as_bytes())
+        }),
+    }
+
+    // Volumes without room are passed over for the next best choice
+    ranked
+        .into_iter()
+        .find(|vol| free(vol) >= size)
+        .ok_or_else(|| VolumeFull {
+            needed: size,
+            available: volumes.iter().map(free).max().unwrap_or(0),
+        })
+}
+
+/// Path to the volume a newly encoded file of `size` bytes should be written to.
+/// `reserved` is room already set aside on volumes for files that aren't recorded yet.
+pub async fn place_storage_path(
+    blake3_hash: &str,
+    size: u64,
+    reserved: &HashMap<PathBuf, u64>,
+) -> Result<PathBuf> {
     let cfg = get_cfg().await?;
+    let mut usage = get_volume_usage().await?;
+    for (volume, bytes) in reserved {
+        *usage.entry(volume.clone()).or_default() += bytes;
+    }
+
+    let volume = select_volume(&cfg.volumes, cfg.placement, &usage, blake3_hash, size)?;
 
-    if cfg.volumes.len() > 1 {
-        unimplemented!();
-    } else {
-        Ok(PathBuf::from(&cfg.volumes[0].path))
+    Ok(volume.path.to_owned())
+}
+
+/// Path to the volume holding an encoded file
+pub async fn get_storage_path(blake3_hash: &str) -> Result<PathBuf> {
+    if let Some(volume) = get_volume(blake3_hash).await? {
+        return Ok(volume);
     }
+
+    // Files encoded before volumes were recorded, or not yet inserted, are found by looking for them
+    let cfg = get_cfg().await?;
+
+    cfg.volumes
+        .iter()
+        .find(|vol| vol.path.join(blake3_hash).exists())
+        .map(|vol| vol.path.to_owned())
+        .ok_or_else(|| anyhow!("Encoded file {} not found on any volume", blake3_hash))
 }
 
 pub async fn get_data_dir() -> Result<PathBuf> {
@@ -0,0 +1,193 @@
+//! # Encryption at rest
+//! File contents are encrypted with XChaCha20-Poly1305 in the STREAM construction, in 64 KiB chunks, before they're bao-encoded.
+//! Verification slices are taken from the ciphertext, so providers can prove possession without being able to read anything.
+use std::io::{self, ErrorKind, Read, Write};
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+
+use crate::db::USR_CONFIG;
+
+const FILE_KEY_CONTEXT: &str = "Forage Storage File Encryption Key";
+
+/// ## Format
+/// A random 19 byte nonce prefix, followed by chunks, each prefixed with its ciphertext length as a big-endian u32.
+/// The high bit of the length marks the final chunk, which is also encrypted under a different nonce so it can't be truncated or moved.
+const CHUNK_LEN: usize = 65536;
+const TAG_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 19;
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// Per-file key, derived from the user's encryption key and the file's keyed hash
+pub fn file_key(blake3_hash: &blake3::Hash) -> Key {
+    let mut hasher = blake3::Hasher::new_derive_key(FILE_KEY_CONTEXT);
+    hasher.update(&USR_CONFIG.encryption_key);
+    hasher.update(blake3_hash.as_bytes());
+    *Key::from_slice(hasher.finalize().as_bytes())
+}
+
+/// Bytes `len` bytes of plaintext take up once they're encrypted: the nonce prefix, then a length and tag for each chunk.
+/// Even empty contents are written as a single empty chunk.
+pub fn encrypted_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_LEN as u64).max(1);
+    NONCE_PREFIX_LEN as u64 + len + chunks * (4 + TAG_LEN as u64)
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Encrypts everything written to it. `finish` must be called to write the final chunk.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(mut inner: W, key: &Key) -> io::Result<Self> {
+        // TODO: replace all RNGs with CSPRNGs
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        inner.write_all(&prefix)?;
+
+        Ok(Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key),
+            prefix,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_LEN),
+        })
+    }
+
+    fn write_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, &self.buf[..len])
+            .map_err(|_| invalid_data("Encryption failed"))?;
+
+        let mut header = ciphertext.len() as u32;
+        if last {
+            header |= LAST_CHUNK;
+        }
+
+        self.inner.write_all(&header.to_be_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        self.buf.drain(..len);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("File too large to encrypt"))?;
+
+        Ok(())
+    }
+
+    /// Writes the final chunk and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(self.buf.len(), true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+
+        // A full chunk is held back until there's more data, since the last chunk is encrypted differently
+        while self.buf.len() > CHUNK_LEN {
+            self.write_chunk(CHUNK_LEN, false)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptingWriter`, stopping after the final chunk
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(mut inner: R, key: &Key) -> io::Result<Self> {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        inner.read_exact(&mut prefix)?;
+
+        Ok(Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key),
+            prefix,
+            counter: 0,
+            buf: vec![],
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 4];
+        self.inner.read_exact(&mut header)?;
+        let header = u32::from_be_bytes(header);
+        let last = header & LAST_CHUNK != 0;
+        let len = (header & !LAST_CHUNK) as usize;
+
+        if !(TAG_LEN..=CHUNK_LEN + TAG_LEN).contains(&len) {
+            return Err(invalid_data("Invalid encrypted chunk length"));
+        }
+
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.buf = self
+            .cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| invalid_data("Decryption failed"))?;
+        self.pos = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.done = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
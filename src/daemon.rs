@@ -0,0 +1,230 @@
+//! # Storage provider daemon
+//! `forage start` listens for storage clients on this node's hidden service, or on its local port when Tor isn't available.
+//! Each client connection is served on its own task until the daemon is told to stop.
+//! Meanwhile, data this node stored is verified periodically.
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use anyhow::Result;
+use chrono::Utc;
+use log::{error, info, warn};
+use rand::Rng;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    alert_failures,
+    config::{get_cfg, VerificationCfg},
+    db::{count_verifications_since, flush_kv, flush_sql, get_channel, get_track_records},
+    file::{verify_data, VerificationReport},
+    net::{auth::ConnectionString, channel, tor::OnionService},
+};
+
+/// How long open connections are given to finish once the daemon is stopping
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Runs the daemon on the configured service port until `shutdown` completes
+pub async fn run(shutdown: impl Future<Output = ()>) -> Result<()> {
+    let cfg = get_cfg().await?;
+    let listener =
+        TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], cfg.tor.service_port))).await?;
+    info!(
+        "Listening for storage clients on {}",
+        listener.local_addr()?
+    );
+
+    // Tor isn't required to serve clients locally, so failing to publish isn't fatal
+    let service = match OnionService::publish(&cfg.tor).await {
+        Ok(service) => {
+            info!("Hidden service published at {}", service.address);
+            Some(service)
+        }
+        Err(e) => {
+            warn!("Hidden service not published: {}", e);
+            None
+        }
+    };
+
+    let scheduler = tokio::spawn(verify_periodically(cfg.verification.clone()));
+
+    // Verification stops before the databases are flushed
+    let served = serve_until(listener, async {
+        shutdown.await;
+        scheduler.abort();
+    })
+    .await;
+
+    if let Some(service) = service {
+        service.unpublish().await?;
+    }
+
+    served
+}
+
+/// Accepts storage clients until `shutdown` completes, then waits for open connections and flushes both databases
+pub async fn serve_until(listener: TcpListener, shutdown: impl Future<Output = ()>) -> Result<()> {
+    // Every connection holds a sender, so the receiver only finishes once they've all closed
+    let (connected, mut finished) = mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Couldn't accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let connected = connected.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = channel::serve(stream).await {
+                        error!("Storage channel from {} closed: {}", peer, e);
+                    }
+                    drop(connected);
+                });
+            }
+        }
+    }
+
+    info!("Stopping Forage node...");
+    drop(listener);
+    drop(connected);
+
+    if timeout(SHUTDOWN_GRACE, finished.recv()).await.is_err() {
+        warn!(
+            "Storage clients still connected after {:?}, stopping anyway",
+            SHUTDOWN_GRACE
+        );
+    }
+
+    flush_kv()?;
+    flush_sql().await?;
+    info!("Forage node stopped");
+
+    Ok(())
+}
+
+/// Runs verification rounds until the daemon stops, backing off while they fail
+pub async fn verify_periodically(cfg: VerificationCfg) {
+    if cfg.interval_minutes == 0 {
+        info!("Periodic verification is turned off");
+        return;
+    }
+
+    let mut failures = 0;
+
+    loop {
+        let delay = next_verification(&cfg, failures);
+        info!("Next verification in {:.0?}", delay);
+        sleep(delay).await;
+
+        match verify_round(&cfg).await {
+            Ok(report) if report.passed() => {
+                info!(
+                    "Verified {} files in {:.2?}",
+                    report.files.len(),
+                    report.elapsed
+                );
+                failures = 0;
+            }
+            Ok(report) => {
+                error!(
+                    "Verification unsuccessful. {} slices failed in {} files checked.",
+                    report.slices_failed(),
+                    report.files.len()
+                );
+                failures += 1;
+            }
+            Err(e) => {
+                error!("Couldn't verify stored data: {}", e);
+                failures += 1;
+            }
+        }
+    }
+}
+
+/// Time until the next verification round. The interval doubles with each failed round in a row, up to the longest backoff.
+pub fn next_verification(cfg: &VerificationCfg, failures: u32) -> Duration {
+    let minutes = cfg
+        .interval_minutes
+        .saturating_mul(1 << failures.min(16))
+        .min(cfg.max_backoff_minutes.max(cfg.interval_minutes));
+    let jitter = rand::thread_rng().gen_range(0..=cfg.jitter_minutes * 60);
+
+    Duration::from_secs(minutes * 60 + jitter)
+}
+
+/// Verifies stored data without asking the storage provider for more slices than its daily budget allows
+async fn verify_round(cfg: &VerificationCfg) -> Result<VerificationReport> {
+    let budget = match get_channel()? {
+        Some(connection) => {
+            let provider = connection.parse::<ConnectionString>()?.provider.to_string();
+            let challenged =
+                count_verifications_since(&provider, Utc::now() - chrono::Duration::days(1))
+                    .await?;
+            Some(cfg.daily_challenges_per_provider.saturating_sub(challenged))
+        }
+        None => None,
+    };
+
+    let report = verify_data(budget).await?;
+
+    if report.skipped > 0 {
+        warn!(
+            "{} files weren't verified, since the storage provider's daily challenge budget ran out",
+            report.skipped
+        );
+    }
+
+    alert_failures(&get_track_records().await?).await?;
+
+    Ok(report)
+}
+
+/// Whether a daemon is accepting storage clients on the configured service port
+pub async fn is_running(service_port: u16) -> bool {
+    let address = SocketAddr::from(([127, 0, 0, 1], service_port));
+    matches!(
+        timeout(Duration::from_secs(1), TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Completes on Ctrl-C, or SIGTERM on Unix
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Couldn't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -1,7 +1,20 @@
-#![allow(dead_code)]
-use std::{cmp, collections::HashSet, convert::TryInto, path::PathBuf, str::FromStr, sync::Arc};
+#![allow(
+    dead_code,
+    clippy::empty_line_after_doc_comments,
+    clippy::useless_conversion
+)]
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use human_bytes::human_bytes;
 use log::error;
@@ -12,11 +25,12 @@ use sled::{Config, Db, IVec, Mode};
 use tokio::sync::Mutex;
 
 use crate::{
-    config::ENV_CFG,
-    hash::{parse_bao_hash, parse_blake3_hash},
+    config::{ENV_CFG, MEGABYTE},
+    hash::{parse_bao_hash, parse_blake3_hash, Compressed},
 };
 
 const HASH_KEY_CONTEXT: &str = "Forage Storage User Hash Key";
+const ENCRYPTION_KEY_CONTEXT: &str = "Forage Storage User Encryption Key";
 
 /// # Databases
 
@@ -25,9 +39,16 @@ const HASH_KEY_CONTEXT: &str = "Forage Storage User Hash Key";
 /// ### Trees / Keys
 const USR_CFG_TREE: &str = "usr_cfg";
 const USR_CFG_HASH_KEY: &str = "hash_key";
+const USR_CFG_ENCRYPTION_KEY: &str = "encryption_key";
+const USR_CFG_DICTIONARY_ID: &str = "dictionary_id";
+const USR_CFG_ONION_KEY: &str = "onion_key";
+const USR_CFG_CHANNEL: &str = "channel";
 
 const PATHS_TREE: &str = "paths";
 const HASH_TREE: &str = "hash";
+const DICTIONARY_TREE: &str = "dictionaries";
+const SCAN_TREE: &str = "scan";
+const CHUNK_TREE: &str = "chunk";
 
 static DB_KV: Lazy<Arc<Db>> = Lazy::new(|| {
     Arc::new(
@@ -76,27 +97,106 @@ static DB_SQL: Lazy<Arc<Mutex<Connection>>> = Lazy::new(|| {
                     date_modified       DATETIME NOT NULL,
                     date_accessed       DATETIME NOT NULL,
                     dropped             BOOLEAN NOT NULL,
-                    removed             BOOLEAN NOT NULL
+                    removed             BOOLEAN NOT NULL,
+                    volume              TEXT,
+                    bytes_compressed    BIGINT,
+                    dictionary_id       INTEGER,
+                    encrypted           BOOLEAN NOT NULL DEFAULT FALSE,
+                    date_verified       DATETIME,
+                    verified            BOOLEAN,
+                    chunked             BOOLEAN NOT NULL DEFAULT FALSE
                 );
                 CREATE TABLE IF NOT EXISTS peers (
                     tor_v3              TEXT NOT NULL,
                     label               TEXT,
                     date_created        DATETIME NOT NULL,
                     client              BOOLEAN NOT NULL,
-                    provider            BOOLEAN NOT NULL
+                    provider            BOOLEAN NOT NULL,
+                    cap                 BIGINT,
+                    bytes_used          BIGINT NOT NULL DEFAULT 0
+                );
+                CREATE TABLE IF NOT EXISTS blobs (
+                    client              TEXT NOT NULL,
+                    blake3_hash         CHARACTER(64) NOT NULL,
+                    volume              TEXT NOT NULL,
+                    bytes               BIGINT NOT NULL,
+                    PRIMARY KEY (client, blake3_hash)
+                );
+                CREATE TABLE IF NOT EXISTS verifications (
+                    date_verified       DATETIME NOT NULL,
+                    peer                TEXT,
+                    blake3_hash         CHARACTER(64) NOT NULL,
+                    slice_index         BIGINT NOT NULL,
+                    passed              BOOLEAN NOT NULL,
+                    latency_ms          BIGINT NOT NULL,
+                    error               TEXT
+                );
+                CREATE TABLE IF NOT EXISTS chunks (
+                    blake3_hash         CHARACTER(64) PRIMARY KEY,
+                    bao_hash            CHARACTER(64) NOT NULL,
+                    bytes_read          BIGINT NOT NULL,
+                    bytes_written       BIGINT NOT NULL,
+                    slices              BIGINT NOT NULL,
+                    volume              TEXT,
+                    bytes_compressed    BIGINT,
+                    dictionary_id       INTEGER,
+                    encrypted           BOOLEAN NOT NULL,
+                    refs                BIGINT NOT NULL DEFAULT 0
+                );
+                CREATE TABLE IF NOT EXISTS manifests (
+                    blake3_hash         CHARACTER(64) NOT NULL,
+                    chunk_index         BIGINT NOT NULL,
+                    chunk_hash          CHARACTER(64) NOT NULL,
+                    PRIMARY KEY (blake3_hash, chunk_index)
                 );
                 CREATE UNIQUE INDEX IF NOT EXISTS idx_file_blake3_hash ON files (blake3_hash);
+                CREATE INDEX IF NOT EXISTS idx_verification_peer ON verifications (peer, date_verified);
                 CREATE UNIQUE INDEX IF NOT EXISTS idx_peer_tor_v3 ON peers (tor_v3);
                 COMMIT;",
     )
     .unwrap();
 
+    add_column(&conn, "files", "volume", "TEXT").unwrap();
+    add_column(&conn, "files", "bytes_compressed", "BIGINT").unwrap();
+    add_column(&conn, "files", "dictionary_id", "INTEGER").unwrap();
+    add_column(
+        &conn,
+        "files",
+        "encrypted",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    )
+    .unwrap();
+    add_column(&conn, "files", "date_verified", "DATETIME").unwrap();
+    add_column(&conn, "files", "verified", "BOOLEAN").unwrap();
+    add_column(&conn, "files", "chunked", "BOOLEAN NOT NULL DEFAULT FALSE").unwrap();
+    add_column(&conn, "peers", "cap", "BIGINT").unwrap();
+    add_column(&conn, "peers", "bytes_used", "BIGINT NOT NULL DEFAULT 0").unwrap();
+
     Arc::new(Mutex::new(conn))
 });
 
+/// ### Adds columns introduced after a table was first created
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
 /// ## Persisted User Config
 pub struct UsrCfg {
     pub hash_key: [u8; 32],
+    pub encryption_key: [u8; 32],
 }
 
 fn fix_slice<const N: usize>(slice: &[u8]) -> [u8; N] {
@@ -105,26 +205,74 @@ fn fix_slice<const N: usize>(slice: &[u8]) -> [u8; N] {
     fixed_arr
 }
 
-/// ### Hash key for keyed hashes is generated and then persisted so data can be de-duplicated deterministically without revealing the original hash
-fn init_usr_cfg() -> Result<UsrCfg> {
-    let hash_key: [u8; 32] = match DB_KV.open_tree(USR_CFG_TREE)?.get(USR_CFG_HASH_KEY)? {
-        Some(fs) => fix_slice::<32>(&fs),
+/// ### Keys are generated once from random key material and then persisted
+fn get_or_init_key(name: &str, context: &str) -> Result<[u8; 32]> {
+    match DB_KV.open_tree(USR_CFG_TREE)?.get(name)? {
+        Some(fs) => Ok(fix_slice::<32>(&fs)),
         None => {
             let mut rng = rand::thread_rng();
             let mut key_material = vec![0; 32];
             rng.fill_bytes(&mut key_material);
 
-            let hash_key = blake3::derive_key(HASH_KEY_CONTEXT, &key_material);
+            let key = blake3::derive_key(context, &key_material);
 
             DB_KV
                 .open_tree(USR_CFG_TREE)?
-                .insert(USR_CFG_HASH_KEY, IVec::from(&hash_key))?;
+                .insert(name, IVec::from(&key))?;
 
-            hash_key
+            Ok(key)
         }
-    };
+    }
+}
+
+/// ### Hash key for keyed hashes is generated and then persisted so data can be de-duplicated deterministically without revealing the original hash
+/// ### Encryption key is the root that per-file encryption keys are derived from
+fn init_usr_cfg() -> Result<UsrCfg> {
+    let hash_key = get_or_init_key(USR_CFG_HASH_KEY, HASH_KEY_CONTEXT)?;
+    let encryption_key = get_or_init_key(USR_CFG_ENCRYPTION_KEY, ENCRYPTION_KEY_CONTEXT)?;
 
-    Ok(UsrCfg { hash_key })
+    Ok(UsrCfg {
+        hash_key,
+        encryption_key,
+    })
+}
+
+/// ### Onion v3 secret key for this node's hidden service
+pub fn get_onion_key() -> Result<Option<[u8; 64]>> {
+    Ok(DB_KV
+        .open_tree(USR_CFG_TREE)?
+        .get(USR_CFG_ONION_KEY)?
+        .map(|key| fix_slice::<64>(&key)))
+}
+
+pub fn insert_onion_key(key: &[u8; 64]) -> Result<()> {
+    DB_KV
+        .open_tree(USR_CFG_TREE)?
+        .insert(USR_CFG_ONION_KEY, &key[..])?;
+    DB_KV.flush()?;
+    Ok(())
+}
+
+/// ### Connection string of the storage channel opened to a provider, if one was opened
+pub fn get_channel() -> Result<Option<String>> {
+    Ok(DB_KV
+        .open_tree(USR_CFG_TREE)?
+        .get(USR_CFG_CHANNEL)?
+        .map(|channel| String::from_utf8_lossy(&channel).to_string()))
+}
+
+pub fn insert_channel(connection: &str) -> Result<()> {
+    DB_KV
+        .open_tree(USR_CFG_TREE)?
+        .insert(USR_CFG_CHANNEL, connection.as_bytes())?;
+    DB_KV.flush()?;
+    Ok(())
+}
+
+pub fn remove_channel() -> Result<()> {
+    DB_KV.open_tree(USR_CFG_TREE)?.remove(USR_CFG_CHANNEL)?;
+    DB_KV.flush()?;
+    Ok(())
 }
 
 pub static USR_CONFIG: Lazy<UsrCfg> = Lazy::new(|| init_usr_cfg().unwrap());
@@ -134,25 +282,63 @@ pub static USR_CONFIG: Lazy<UsrCfg> = Lazy::new(|| init_usr_cfg().unwrap());
 /// ## Files
 
 /// ### File Info struct
+/// A chunked file has no encoding of its own, since its contents are stored in the chunks listed in its manifest.
+/// Its bao hash is a hash of its chunks' hashes, it has no volume, and its chunks record how they were compressed and encrypted.
+#[derive(Clone)]
 pub struct FileInfo {
     pub blake3_hash: blake3::Hash, // Primary key
-    pub bao_hash: bao::Hash,
-    pub bytes_read: u64,    // original bytes on disk
-    pub bytes_written: u64, // bao-encoded bytes on disk
-    pub min_slice: u64,     // starting slice index
-    pub max_slice: u64,     // ending slice index
+    pub bao_hash: bao::Hash,       // Hash of the manifest, if chunked
+    pub bytes_read: u64,           // original bytes on disk
+    pub bytes_written: u64,        // bao-encoded bytes on disk
+    pub min_slice: u64,            // first global slice index
+    pub max_slice: u64, // global slice index after the last, so ranges of adjacent files don't overlap
     pub path: PathBuf,
     pub parent_rev: Option<blake3::Hash>,
     pub mime_type: String,
     pub date_created: DateTime<Utc>,
     pub date_modified: DateTime<Utc>,
     pub date_accessed: DateTime<Utc>,
-    pub dropped: bool, // Dropped from storage client
+    pub dropped: bool,                  // Dropped from storage client
     pub removed: bool, // Removed from storage provider (but still tracked for verification)
+    pub volume: Option<PathBuf>, // Storage volume holding the encoded file
+    pub compressed: Option<Compressed>, // Set if contents were compressed before encoding
+    pub encrypted: bool, // Contents were encrypted before encoding
+    pub chunked: bool, // Split into chunks, stored by themselves
 }
 
 /// ### Adds a file to SQL DB
+/// Contents uploaded again after their revision was removed take over its row, stored again under newly allocated slices.
+/// The removed revision's slice range is left unused rather than handed out again.
 pub async fn insert_file(file: FileInfo) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    insert_file_row(&conn, file)
+}
+
+/// Inserts newly uploaded files in a single transaction, dropping the revisions they replace.
+/// Chunked files are inserted along with their manifests, and the chunks first stored with them.
+pub async fn insert_files(files: Vec<(FileInfo, Option<Manifest>)>) -> Result<()> {
+    let mut conn = DB_SQL.lock().await;
+    let tx = conn.transaction()?;
+
+    for (file, manifest) in files {
+        let parent_rev = file.parent_rev;
+
+        if let Some(manifest) = manifest {
+            insert_manifest_rows(&tx, &file.blake3_hash, manifest)?;
+        }
+        insert_file_row(&tx, file)?;
+
+        if let Some(parent_hash) = parent_rev {
+            mark_row_as_dropped(&tx, parent_hash)?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn insert_file_row(conn: &Connection, file: FileInfo) -> Result<()> {
     let blake3_hash: String = file.blake3_hash.to_hex().to_string();
     let bao_hash: String = file.bao_hash.to_hex().to_string();
     let bytes_read: u64 = file.bytes_read;
@@ -167,8 +353,11 @@ pub async fn insert_file(file: FileInfo) -> Result<()> {
     let date_accessed: i64 = file.date_accessed.timestamp_millis();
     let dropped: bool = file.dropped;
     let removed: bool = file.removed;
-
-    let conn = DB_SQL.lock().await;
+    let volume: Option<String> = file.volume.map(|v| v.to_string_lossy().to_string());
+    let bytes_compressed: Option<u64> = file.compressed.map(|c| c.len);
+    let dictionary_id: Option<u32> = file.compressed.and_then(|c| c.dictionary_id);
+    let encrypted: bool = file.encrypted;
+    let chunked: bool = file.chunked;
 
     let mut stmt = conn.prepare_cached(
         "   INSERT INTO files (
@@ -185,7 +374,12 @@ pub async fn insert_file(file: FileInfo) -> Result<()> {
                     date_modified,
                     date_accessed,
                     dropped,
-                    removed
+                    removed,
+                    volume,
+                    bytes_compressed,
+                    dictionary_id,
+                    encrypted,
+                    chunked
                 ) VALUES (
                     :blake3_hash,
                     :bao_hash,
@@ -200,11 +394,38 @@ pub async fn insert_file(file: FileInfo) -> Result<()> {
                     :date_modified,
                     :date_accessed,
                     :dropped,
-                    :removed
-                )",
+                    :removed,
+                    :volume,
+                    :bytes_compressed,
+                    :dictionary_id,
+                    :encrypted,
+                    :chunked
+                )
+                ON CONFLICT (blake3_hash) DO UPDATE SET
+                    bao_hash = excluded.bao_hash,
+                    bytes_read = excluded.bytes_read,
+                    bytes_written = excluded.bytes_written,
+                    min_slice = excluded.min_slice,
+                    max_slice = excluded.max_slice,
+                    path = excluded.path,
+                    parent_rev = excluded.parent_rev,
+                    mime_type = excluded.mime_type,
+                    date_created = excluded.date_created,
+                    date_modified = excluded.date_modified,
+                    date_accessed = excluded.date_accessed,
+                    dropped = excluded.dropped,
+                    removed = excluded.removed,
+                    volume = excluded.volume,
+                    bytes_compressed = excluded.bytes_compressed,
+                    dictionary_id = excluded.dictionary_id,
+                    encrypted = excluded.encrypted,
+                    chunked = excluded.chunked,
+                    date_verified = NULL,
+                    verified = NULL
+                WHERE files.removed",
     )?;
 
-    stmt.execute(named_params! {
+    let inserted = stmt.execute(named_params! {
         ":blake3_hash": blake3_hash,
         ":bao_hash": bao_hash,
         ":bytes_read": bytes_read,
@@ -219,8 +440,17 @@ pub async fn insert_file(file: FileInfo) -> Result<()> {
         ":date_accessed": date_accessed,
         ":dropped": dropped,
         ":removed": removed,
+        ":volume": volume,
+        ":bytes_compressed": bytes_compressed,
+        ":dictionary_id": dictionary_id,
+        ":encrypted": encrypted,
+        ":chunked": chunked,
     })?;
 
+    if inserted == 0 {
+        return Err(anyhow!("{} is already stored", blake3_hash));
+    }
+
     Ok(())
 }
 
@@ -236,6 +466,89 @@ pub fn upsert_path(file_path: &str, hash_bytes: &[u8]) -> Result<Option<blake3::
         .map(|v| ivec_to_blake3_hash(v).unwrap()))
 }
 
+/// Forgets the path a file was uploaded from, unless a newer revision has been uploaded from it since
+pub fn remove_path(file_path: &str, hash: &blake3::Hash) -> Result<()> {
+    // A mismatch just means the path now belongs to another revision
+    let _ = DB_KV.open_tree(PATHS_TREE)?.compare_and_swap(
+        file_path,
+        Some(hash.as_bytes()),
+        None as Option<&[u8]>,
+    )?;
+    Ok(())
+}
+
+/// Points a path back at the revision uploaded from it before `hash`, or forgets it if there wasn't one, unless a newer revision has been uploaded from it since
+pub fn revert_path(
+    file_path: &str,
+    hash: &blake3::Hash,
+    previous: Option<blake3::Hash>,
+) -> Result<()> {
+    let _ = DB_KV.open_tree(PATHS_TREE)?.compare_and_swap(
+        file_path,
+        Some(hash.as_bytes()),
+        previous.as_ref().map(|previous| previous.as_bytes()),
+    )?;
+    Ok(())
+}
+
+/// ### Scan cache
+/// Files are only hashed again once their size, modification time or inode changes.
+
+/// What a file looked like on disk when it was hashed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub modified: u128, // nanoseconds since the Unix epoch
+    pub inode: u64,     // 0 where inodes aren't available
+}
+
+const FILE_STAT_LEN: usize = 32;
+
+impl FileStat {
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self> {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+            inode,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; FILE_STAT_LEN] {
+        let mut bytes = [0; FILE_STAT_LEN];
+        bytes[..8].copy_from_slice(&self.size.to_be_bytes());
+        bytes[8..24].copy_from_slice(&self.modified.to_be_bytes());
+        bytes[24..].copy_from_slice(&self.inode.to_be_bytes());
+        bytes
+    }
+}
+
+/// Keyed hash of the file at a path, if it was hashed before and still looks the same
+pub fn get_cached_hash(file_path: &str, stat: &FileStat) -> Result<Option<blake3::Hash>> {
+    let entry = match DB_KV.open_tree(SCAN_TREE)?.get(file_path)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    if entry.len() != FILE_STAT_LEN + blake3::OUT_LEN || entry[..FILE_STAT_LEN] != stat.to_bytes() {
+        return Ok(None);
+    }
+
+    let hash_array: [u8; blake3::OUT_LEN] = entry[FILE_STAT_LEN..].try_into()?;
+    Ok(Some(hash_array.into()))
+}
+
+pub fn cache_hash(file_path: &str, stat: &FileStat, hash: &blake3::Hash) -> Result<()> {
+    let mut entry = stat.to_bytes().to_vec();
+    entry.extend_from_slice(hash.as_bytes());
+    DB_KV.open_tree(SCAN_TREE)?.insert(file_path, entry)?;
+    Ok(())
+}
+
 pub fn insert_hash(hash_bytes: &[u8]) -> Result<()> {
     DB_KV
         .open_tree(HASH_TREE)?
@@ -247,11 +560,44 @@ pub fn contains_hash(hash_bytes: &[u8]) -> Result<bool> {
     Ok(DB_KV.open_tree(HASH_TREE)?.contains_key(hash_bytes)?)
 }
 
+/// ### Stores a trained compression dictionary and makes it the one used for newly encoded files
+pub fn insert_dictionary(dictionary_id: u32, dictionary: &[u8]) -> Result<()> {
+    DB_KV
+        .open_tree(DICTIONARY_TREE)?
+        .insert(dictionary_id.to_be_bytes(), dictionary)?;
+    DB_KV
+        .open_tree(USR_CFG_TREE)?
+        .insert(USR_CFG_DICTIONARY_ID, &dictionary_id.to_be_bytes())?;
+    Ok(())
+}
+
+/// Dictionaries are kept after they're replaced, since older files still need them to be decompressed
+pub fn get_dictionary(dictionary_id: u32) -> Result<Option<IVec>> {
+    Ok(DB_KV
+        .open_tree(DICTIONARY_TREE)?
+        .get(dictionary_id.to_be_bytes())?)
+}
+
+pub fn get_active_dictionary() -> Result<Option<(u32, IVec)>> {
+    match DB_KV.open_tree(USR_CFG_TREE)?.get(USR_CFG_DICTIONARY_ID)? {
+        Some(id) => {
+            let dictionary_id = u32::from_be_bytes(fix_slice::<4>(&id));
+            Ok(get_dictionary(dictionary_id)?.map(|dictionary| (dictionary_id, dictionary)))
+        }
+        None => Ok(None),
+    }
+}
+
 pub fn flush_kv() -> Result<()> {
     DB_KV.flush()?;
     Ok(())
 }
 
+pub async fn flush_sql() -> Result<()> {
+    DB_SQL.lock().await.cache_flush()?;
+    Ok(())
+}
+
 type BlakeHashSet = HashSet<blake3::Hash>;
 
 fn join_set(set: BlakeHashSet) -> String {
@@ -268,6 +614,68 @@ fn diff_set(set_a: BlakeHashSet, set_b: &BlakeHashSet) -> BlakeHashSet {
     set_a.difference(set_b).into_iter().copied().collect()
 }
 
+/// Dates are stored as milliseconds since the Unix epoch
+fn datetime_from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_utc(
+        NaiveDateTime::from_timestamp(
+            millis.div_euclid(1000),
+            (millis.rem_euclid(1000) * 1_000_000) as u32,
+        ),
+        Utc,
+    )
+}
+
+fn file_info_from_row(row: &rusqlite::Row) -> rusqlite::Result<FileInfo> {
+    let blake3_hash: String = row.get("blake3_hash")?;
+    let bao_hash: String = row.get("bao_hash")?;
+    let bytes_read: u64 = row.get("bytes_read")?;
+    let bytes_written: u64 = row.get("bytes_written")?;
+    let min_slice: u64 = row.get("min_slice")?;
+    let max_slice: u64 = row.get("max_slice")?;
+    let path: String = row.get("path")?;
+    let parent_rev: Option<String> = row.get("parent_rev")?;
+    let mime_type = row.get("mime_type")?;
+    let date_created: i64 = row.get("date_created")?;
+    let date_modified: i64 = row.get("date_modified")?;
+    let date_accessed: i64 = row.get("date_accessed")?;
+    let dropped: bool = row.get("dropped")?;
+    let removed: bool = row.get("removed")?;
+    let volume: Option<String> = row.get("volume")?;
+    let bytes_compressed: Option<u64> = row.get("bytes_compressed")?;
+    let dictionary_id: Option<u32> = row.get("dictionary_id")?;
+    let encrypted: bool = row.get("encrypted")?;
+    let chunked: bool = row.get("chunked")?;
+
+    let blake3_hash = parse_blake3_hash(&blake3_hash).unwrap();
+    let bao_hash = parse_bao_hash(&bao_hash).unwrap();
+    let path = PathBuf::from_str(&path).unwrap();
+    let parent_rev = parent_rev.map(|pr| parse_blake3_hash(&pr).unwrap());
+    let date_created = datetime_from_millis(date_created);
+    let date_modified = datetime_from_millis(date_modified);
+    let date_accessed = datetime_from_millis(date_accessed);
+
+    Ok(FileInfo {
+        blake3_hash,
+        bao_hash,
+        bytes_read,
+        bytes_written,
+        min_slice,
+        max_slice,
+        path,
+        parent_rev,
+        mime_type,
+        date_created,
+        date_modified,
+        date_accessed,
+        dropped,
+        removed,
+        volume: volume.map(PathBuf::from),
+        compressed: bytes_compressed.map(|len| Compressed { len, dictionary_id }),
+        encrypted,
+        chunked,
+    })
+}
+
 /// Accepts optional comma-separated strings for specific hashes to retrieve, or omit
 pub async fn get_files(
     include: Option<HashSet<blake3::Hash>>,
@@ -291,78 +699,70 @@ pub async fn get_files(
 
     let mut stmt = conn.prepare(&query)?;
 
-    let results = stmt.query_map([], |row| {
-        let blake3_hash: String = row.get("blake3_hash")?;
-        let bao_hash: String = row.get("bao_hash")?;
-        let bytes_read: u64 = row.get("bytes_read")?;
-        let bytes_written: u64 = row.get("bytes_written")?;
-        let min_slice: u64 = row.get("min_slice")?;
-        let max_slice: u64 = row.get("max_slice")?;
-        let path: String = row.get("path")?;
-        let parent_rev: Option<String> = row.get("parent_rev")?;
-        let mime_type = row.get("mime_type")?;
-        let date_created: i64 = row.get("date_created")?;
-        let date_modified: i64 = row.get("date_modified")?;
-        let date_accessed: i64 = row.get("date_accessed")?;
-        let dropped: bool = row.get("dropped")?;
-        let removed: bool = row.get("removed")?;
-
-        let blake3_hash = parse_blake3_hash(&blake3_hash).unwrap();
-        let bao_hash = parse_bao_hash(&bao_hash).unwrap();
-        let path = PathBuf::from_str(&path).unwrap();
-        let parent_rev = parent_rev.map(|pr| parse_blake3_hash(&pr).unwrap());
-        let date_created = DateTime::from_utc(NaiveDateTime::from_timestamp(date_created, 0), Utc);
-        let date_modified =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(date_modified, 0), Utc);
-        let date_accessed =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(date_accessed, 0), Utc);
-
-        Ok(FileInfo {
-            blake3_hash,
-            bao_hash,
-            bytes_read,
-            bytes_written,
-            min_slice,
-            max_slice,
-            path,
-            parent_rev,
-            mime_type,
-            date_created,
-            date_modified,
-            date_accessed,
-            dropped,
-            removed,
-        })
-    })?;
+    let results = stmt.query_map([], file_info_from_row)?;
 
     Ok(results.map(|res_fi| res_fi.unwrap()).collect())
 }
 
-pub async fn list_files() -> Result<Vec<String>> {
-    let files = get_files(None, None).await?;
+/// Any revision of a file, including dropped and removed ones
+pub async fn get_file(blake3_hash: &blake3::Hash) -> Result<Option<FileInfo>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM files
+                WHERE blake3_hash = :blake3_hash",
+    )?;
 
-    let max_path_len = files.iter().fold(0, |acc, info| {
-        cmp::max(acc, info.path.to_string_lossy().len())
-    });
+    Ok(stmt
+        .query_row(
+            named_params! { ":blake3_hash": blake3_hash.to_hex().to_string() },
+            file_info_from_row,
+        )
+        .optional()?)
+}
 
-    Ok(files
-        .iter()
-        .map(|info| {
-            let path = info.path.to_string_lossy();
+/// Most recently uploaded revision of a path, even if it's since been dropped
+pub async fn get_latest_revision(path: &Path) -> Result<Option<FileInfo>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM files
+                WHERE path = :path
+                ORDER BY rowid DESC
+                LIMIT 1",
+    )?;
 
-            format!(
-                "{size}\t\t{mime_type}\t{path}{path_space}",
-                size = human_bytes(info.bytes_read as f64),
-                mime_type = info.mime_type,
-                path = path,
-                path_space = " ".repeat(max_path_len - path.len()),
-            )
-        })
-        .collect())
+    Ok(stmt
+        .query_row(
+            named_params! { ":path": path.to_string_lossy() },
+            file_info_from_row,
+        )
+        .optional()?)
+}
+
+/// Every revision that can still be retrieved of paths starting with a prefix, oldest first
+pub async fn get_revisions_by_prefix(prefix: &str) -> Result<Vec<FileInfo>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM files
+                WHERE substr(path, 1, length(:prefix)) = :prefix AND removed = FALSE
+                ORDER BY path, rowid",
+    )?;
+
+    let revisions = stmt
+        .query_map(named_params! { ":prefix": prefix }, file_info_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(revisions)
 }
 
 pub async fn mark_as_dropped(blake3_hash: blake3::Hash) -> Result<()> {
     let conn = DB_SQL.lock().await;
+    mark_row_as_dropped(&conn, blake3_hash)
+}
+
+fn mark_row_as_dropped(conn: &Connection, blake3_hash: blake3::Hash) -> Result<()> {
     let mut stmt = conn.prepare_cached(
         "   UPDATE files
                 SET dropped = true
@@ -397,64 +797,247 @@ pub fn remove_hash(hash: blake3::Hash) -> Result<()> {
     Ok(())
 }
 
-pub struct SliceIndexInfo {
-    pub blake3_hash: String,
-    pub bao_hash: String,
-    pub file_slice_index: u64,
-    pub data_dir_path: String,
+/// Volume an encoded file or chunk was placed on, if it was recorded
+pub async fn get_volume(blake3_hash: &str) -> Result<Option<PathBuf>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT volume
+                FROM files
+                WHERE blake3_hash = :blake3_hash
+                UNION ALL
+                SELECT volume
+                FROM chunks
+                WHERE blake3_hash = :blake3_hash
+                LIMIT 1",
+    )?;
+
+    let volume: Option<Option<String>> = stmt
+        .query_row(named_params! { ":blake3_hash": blake3_hash }, |row| {
+            row.get(0)
+        })
+        .optional()?;
+
+    Ok(volume.flatten().map(PathBuf::from))
 }
 
-pub async fn get_max_slice() -> Result<u64> {
+/// Encoded bytes stored on each volume, not counting removed files
+pub async fn get_volume_usage() -> Result<HashMap<PathBuf, u64>> {
     let conn = DB_SQL.lock().await;
+    // Includes chunks, which are only counted once however many files share them, and files stored for storage clients
     let mut stmt = conn.prepare_cached(
-        "   SELECT MAX(max_slice)
-                FROM files
-                WHERE removed = FALSE",
+        "   SELECT volume, SUM(bytes)
+                FROM (
+                    SELECT volume, bytes_written AS bytes
+                    FROM files
+                    WHERE removed = FALSE AND volume IS NOT NULL
+                    UNION ALL
+                    SELECT volume, bytes_written AS bytes
+                    FROM chunks
+                    WHERE volume IS NOT NULL
+                    UNION ALL
+                    SELECT volume, bytes
+                    FROM blobs
+                )
+                GROUP BY volume",
     )?;
 
-    let max_slice = stmt
-        .query_row(params![], |row| row.get(0))
-        .optional()
-        .unwrap_or(Some(0))
-        .unwrap();
+    let usage = stmt
+        .query_map([], |row| {
+            let volume: String = row.get(0)?;
+            let bytes_written: u64 = row.get(1)?;
+            Ok((PathBuf::from(volume), bytes_written))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(usage)
+}
 
-    Ok(max_slice)
+/// ### Totals across every file ever uploaded
+pub struct FileStats {
+    pub files: u64,
+    pub dropped: u64,
+    pub removed: u64,
+    pub bytes_read: u64,    // Original bytes of files still stored
+    pub bytes_written: u64, // Encoded bytes of files still stored
 }
 
-pub async fn get_random_slice_index(max_slice: u64) -> Result<SliceIndexInfo> {
+pub async fn get_file_stats() -> Result<FileStats> {
     let conn = DB_SQL.lock().await;
     let mut stmt = conn.prepare_cached(
-        "   SELECT blake3_hash, bao_hash, path
+        "   SELECT
+                    COUNT(*),
+                    COALESCE(SUM(dropped), 0),
+                    COALESCE(SUM(removed), 0),
+                    COALESCE(SUM(CASE WHEN removed THEN 0 ELSE bytes_read END), 0),
+                    COALESCE(SUM(CASE WHEN removed THEN 0 ELSE bytes_written END), 0)
+                FROM files",
+    )?;
+
+    let stats = stmt.query_row([], |row| {
+        Ok(FileStats {
+            files: row.get(0)?,
+            dropped: row.get(1)?,
+            removed: row.get(2)?,
+            bytes_read: row.get(3)?,
+            bytes_written: row.get(4)?,
+        })
+    })?;
+
+    Ok(stats)
+}
+
+/// A stored file picked for verification
+pub struct SampledFile {
+    pub file: FileInfo,
+    pub slice_indices: BTreeSet<u64>, // Slices it was drawn at, as offsets into the file rather than global slice indices
+}
+
+/// Stored files to verify: the oldest and newest uploaded, and the rest drawn at random slices across all stored files.
+/// Drawing slices picks files in proportion to their size. Slices of removed files are skipped, so gaps they leave are never drawn.
+pub async fn get_verification_sample(count: usize) -> Result<Vec<SampledFile>> {
+    let conn = DB_SQL.lock().await;
+    let mut sample: BTreeMap<i64, BTreeSet<u64>> = BTreeMap::new();
+
+    let (oldest, newest): (Option<i64>, Option<i64>) = conn
+        .prepare_cached(
+            "   SELECT MIN(rowid), MAX(rowid)
                 FROM files
-                WHERE
-                    min_slice <= :slice_index AND
-                    max_slice >= :slice_index AND
-                    removed = FALSE",
+                WHERE removed = FALSE",
+        )?
+        .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    for rowid in [oldest, newest].into_iter().flatten().take(count) {
+        sample.entry(rowid).or_default();
+    }
+
+    let live_slices: u64 = conn
+        .prepare_cached(
+            "   SELECT COALESCE(SUM(max_slice - min_slice), 0)
+                FROM files
+                WHERE removed = FALSE",
+        )?
+        .query_row([], |row| row.get(0))?;
+
+    // Positions count only slices of files still stored, so they're translated to a file and an offset into it
+    let mut locate = conn.prepare_cached(
+        "   WITH live AS (
+                    SELECT
+                        rowid,
+                        max_slice - min_slice AS slices,
+                        SUM(max_slice - min_slice) OVER (ORDER BY rowid) AS slice_end
+                    FROM files
+                    WHERE removed = FALSE
+                )
+                SELECT rowid, :position - (slice_end - slices) AS slice_index
+                FROM live
+                WHERE slices > 0 AND slice_end > :position
+                ORDER BY slice_end
+                LIMIT 1",
     )?;
 
     // TODO: replace all RNGs with CSPRNGs
     let mut rng = rand::thread_rng();
-    let file_slice_index = rng.gen_range(0..max_slice);
-
-    let result = stmt.query_row(
-        named_params! {
-            ":slice_index": file_slice_index,
-        },
-        |row| {
-            let blake3_hash: String = row.get("blake3_hash")?;
-            let bao_hash: String = row.get("bao_hash")?;
-            let data_dir_path: String = row.get("path")?;
-
-            Ok(SliceIndexInfo {
-                blake3_hash,
-                bao_hash,
-                file_slice_index,
-                data_dir_path,
+
+    // Draws can land on files already sampled, so a few more are allowed than files are needed
+    for _ in 0..count * 4 {
+        if sample.len() >= count || live_slices == 0 {
+            break;
+        }
+
+        let position = rng.gen_range(0..live_slices);
+        let drawn: Option<(i64, u64)> = locate
+            .query_row(named_params! { ":position": position }, |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+
+        if let Some((rowid, slice_index)) = drawn {
+            sample.entry(rowid).or_default().insert(slice_index);
+        }
+    }
+
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM files
+                WHERE rowid = :rowid",
+    )?;
+
+    sample
+        .into_iter()
+        .map(|(rowid, slice_indices)| {
+            Ok(SampledFile {
+                file: stmt.query_row(named_params! { ":rowid": rowid }, file_info_from_row)?,
+                slice_indices,
+            })
+        })
+        .collect()
+}
+
+/// Records the outcome of the latest slice verification of a file
+pub async fn mark_as_verified(blake3_hash: &str, verified: bool) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   UPDATE files
+                SET date_verified = :date_verified, verified = :verified
+                WHERE blake3_hash = :blake3_hash",
+    )?;
+
+    stmt.execute(named_params! {
+        ":blake3_hash": blake3_hash,
+        ":date_verified": Utc::now().timestamp_millis(),
+        ":verified": verified,
+    })?;
+
+    Ok(())
+}
+
+pub struct VerificationInfo {
+    pub blake3_hash: String,
+    pub path: PathBuf,
+    pub date_verified: DateTime<Utc>,
+    pub verified: bool,
+}
+
+/// Most recently verified file, if any have been
+pub async fn get_last_verification() -> Result<Option<VerificationInfo>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT blake3_hash, path, date_verified, verified
+                FROM files
+                WHERE date_verified IS NOT NULL
+                ORDER BY date_verified DESC
+                LIMIT 1",
+    )?;
+
+    let verification = stmt
+        .query_row([], |row| {
+            let path: String = row.get("path")?;
+            let date_verified: i64 = row.get("date_verified")?;
+
+            Ok(VerificationInfo {
+                blake3_hash: row.get("blake3_hash")?,
+                path: PathBuf::from(path),
+                date_verified: datetime_from_millis(date_verified),
+                verified: row.get("verified")?,
             })
-        },
+        })
+        .optional()?;
+
+    Ok(verification)
+}
+
+/// First global slice index not yet allocated to a file.
+/// Ranges of removed files aren't handed out again, so a slice index only ever refers to one file.
+pub async fn get_next_slice() -> Result<u64> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT COALESCE(MAX(max_slice), 0)
+                FROM files",
     )?;
 
-    Ok(result)
+    let next_slice = stmt.query_row([], |row| row.get(0))?;
+
+    Ok(next_slice)
 }
 
 pub async fn get_hashes_by_prefix(
@@ -472,3 +1055,648 @@ pub async fn get_hashes_by_prefix(
 
     Ok(hashes)
 }
+
+/// ## Chunks
+
+/// ### Chunk Info struct
+#[derive(Clone)]
+pub struct ChunkInfo {
+    pub blake3_hash: blake3::Hash, // Primary key, keyed hash of the chunk's contents that its encoding is stored under
+    pub bao_hash: bao::Hash,
+    pub bytes_read: u64,         // original bytes of the chunk
+    pub bytes_written: u64,      // bao-encoded bytes on disk
+    pub slices: u64, // slices that can be challenged, counted towards each file it's part of
+    pub volume: Option<PathBuf>, // Storage volume holding the encoded chunk
+    pub compressed: Option<Compressed>,
+    pub encrypted: bool,
+}
+
+/// ### Chunks a file was split into, for files uploaded with content-defined chunking
+pub struct Manifest {
+    pub chunks: Vec<blake3::Hash>, // In file order, repeating chunks that appear more than once
+    pub new_chunks: Vec<ChunkInfo>, // Chunks first stored along with the file
+}
+
+/// Each time a chunk appears in a manifest counts as a reference to it
+fn insert_manifest_rows(
+    conn: &Connection,
+    blake3_hash: &blake3::Hash,
+    manifest: Manifest,
+) -> Result<()> {
+    let mut insert_chunk = conn.prepare_cached(
+        "   INSERT INTO chunks (
+                    blake3_hash,
+                    bao_hash,
+                    bytes_read,
+                    bytes_written,
+                    slices,
+                    volume,
+                    bytes_compressed,
+                    dictionary_id,
+                    encrypted
+                ) VALUES (
+                    :blake3_hash,
+                    :bao_hash,
+                    :bytes_read,
+                    :bytes_written,
+                    :slices,
+                    :volume,
+                    :bytes_compressed,
+                    :dictionary_id,
+                    :encrypted
+                )
+                ON CONFLICT (blake3_hash) DO NOTHING",
+    )?;
+
+    for chunk in manifest.new_chunks {
+        insert_chunk.execute(named_params! {
+            ":blake3_hash": chunk.blake3_hash.to_hex().to_string(),
+            ":bao_hash": chunk.bao_hash.to_hex().to_string(),
+            ":bytes_read": chunk.bytes_read,
+            ":bytes_written": chunk.bytes_written,
+            ":slices": chunk.slices,
+            ":volume": chunk.volume.map(|v| v.to_string_lossy().to_string()),
+            ":bytes_compressed": chunk.compressed.map(|c| c.len),
+            ":dictionary_id": chunk.compressed.and_then(|c| c.dictionary_id),
+            ":encrypted": chunk.encrypted,
+        })?;
+    }
+
+    let mut insert_entry = conn.prepare_cached(
+        "   INSERT INTO manifests (
+                    blake3_hash,
+                    chunk_index,
+                    chunk_hash
+                ) VALUES (
+                    :blake3_hash,
+                    :chunk_index,
+                    :chunk_hash
+                )",
+    )?;
+    let mut add_ref = conn.prepare_cached(
+        "   UPDATE chunks
+                SET refs = refs + 1
+                WHERE blake3_hash = :chunk_hash",
+    )?;
+    let blake3_hash = blake3_hash.to_hex().to_string();
+
+    for (chunk_index, chunk_hash) in manifest.chunks.iter().enumerate() {
+        let chunk_hash = chunk_hash.to_hex().to_string();
+        insert_entry.execute(named_params! {
+            ":blake3_hash": blake3_hash,
+            ":chunk_index": chunk_index as u64,
+            ":chunk_hash": chunk_hash,
+        })?;
+        add_ref.execute(named_params! { ":chunk_hash": chunk_hash })?;
+    }
+
+    Ok(())
+}
+
+fn chunk_info_from_row(row: &rusqlite::Row) -> rusqlite::Result<ChunkInfo> {
+    let blake3_hash: String = row.get("blake3_hash")?;
+    let bao_hash: String = row.get("bao_hash")?;
+    let volume: Option<String> = row.get("volume")?;
+    let bytes_compressed: Option<u64> = row.get("bytes_compressed")?;
+    let dictionary_id: Option<u32> = row.get("dictionary_id")?;
+
+    Ok(ChunkInfo {
+        blake3_hash: parse_blake3_hash(&blake3_hash).unwrap(),
+        bao_hash: parse_bao_hash(&bao_hash).unwrap(),
+        bytes_read: row.get("bytes_read")?,
+        bytes_written: row.get("bytes_written")?,
+        slices: row.get("slices")?,
+        volume: volume.map(PathBuf::from),
+        compressed: bytes_compressed.map(|len| Compressed { len, dictionary_id }),
+        encrypted: row.get("encrypted")?,
+    })
+}
+
+/// Chunks of a chunked file, in order
+pub async fn get_manifest(blake3_hash: &blake3::Hash) -> Result<Vec<ChunkInfo>> {
+    let conn = DB_SQL.lock().await;
+    let blake3_hash = blake3_hash.to_hex().to_string();
+
+    let chunks = conn
+        .prepare_cached(
+            "   SELECT chunks.*
+                FROM manifests
+                JOIN chunks ON chunks.blake3_hash = manifests.chunk_hash
+                WHERE manifests.blake3_hash = :blake3_hash
+                ORDER BY manifests.chunk_index",
+        )?
+        .query_map(
+            named_params! { ":blake3_hash": blake3_hash },
+            chunk_info_from_row,
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let entries: usize = conn
+        .prepare_cached(
+            "   SELECT COUNT(*)
+                FROM manifests
+                WHERE blake3_hash = :blake3_hash",
+        )?
+        .query_row(named_params! { ":blake3_hash": blake3_hash }, |row| {
+            row.get(0)
+        })?;
+
+    // A file missing any of its chunks can't be reassembled
+    if chunks.len() != entries {
+        return Err(anyhow!(
+            "{} of {} chunks of {} are missing",
+            entries - chunks.len(),
+            entries,
+            blake3_hash
+        ));
+    }
+
+    Ok(chunks)
+}
+
+/// Distinct chunks of a chunked file, each with how many references the file holds to it, and how many it has in all
+pub async fn get_chunk_refs(blake3_hash: &blake3::Hash) -> Result<Vec<(ChunkInfo, u64, u64)>> {
+    let conn = DB_SQL.lock().await;
+
+    let chunks = conn
+        .prepare_cached(
+            "   SELECT chunks.*, COUNT(*) AS file_refs
+                FROM manifests
+                JOIN chunks ON chunks.blake3_hash = manifests.chunk_hash
+                WHERE manifests.blake3_hash = :blake3_hash
+                GROUP BY chunks.blake3_hash",
+        )?
+        .query_map(
+            named_params! { ":blake3_hash": blake3_hash.to_hex().to_string() },
+            |row| {
+                Ok((
+                    chunk_info_from_row(row)?,
+                    row.get("file_refs")?,
+                    row.get("refs")?,
+                ))
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(chunks)
+}
+
+/// Forgets a file's manifest, releasing its references to its chunks.
+/// Returns the chunks no other file refers to anymore, which are forgotten too, so their encodings can be deleted.
+pub async fn release_manifest(blake3_hash: &blake3::Hash) -> Result<Vec<ChunkInfo>> {
+    let mut conn = DB_SQL.lock().await;
+    let tx = conn.transaction()?;
+    let params = named_params! { ":blake3_hash": blake3_hash.to_hex().to_string() };
+
+    tx.prepare_cached(
+        "   UPDATE chunks
+                SET refs = refs - (
+                    SELECT COUNT(*)
+                    FROM manifests
+                    WHERE blake3_hash = :blake3_hash AND chunk_hash = chunks.blake3_hash
+                )
+                WHERE blake3_hash IN (
+                    SELECT chunk_hash FROM manifests WHERE blake3_hash = :blake3_hash
+                )",
+    )?
+    .execute(params)?;
+
+    let released = tx
+        .prepare_cached(
+            "   SELECT *
+                FROM chunks
+                WHERE refs <= 0 AND blake3_hash IN (
+                    SELECT chunk_hash FROM manifests WHERE blake3_hash = :blake3_hash
+                )",
+        )?
+        .query_map(params, chunk_info_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    tx.prepare_cached(
+        "   DELETE FROM chunks
+                WHERE refs <= 0 AND blake3_hash IN (
+                    SELECT chunk_hash FROM manifests WHERE blake3_hash = :blake3_hash
+                )",
+    )?
+    .execute(params)?;
+
+    tx.prepare_cached(
+        "   DELETE FROM manifests
+                WHERE blake3_hash = :blake3_hash",
+    )?
+    .execute(params)?;
+
+    tx.commit()?;
+
+    Ok(released)
+}
+
+/// Records a stored chunk along with its slice count, so files sharing it can be recorded before its row is inserted
+pub fn insert_chunk(hash: &blake3::Hash, slices: u64) -> Result<()> {
+    DB_KV
+        .open_tree(CHUNK_TREE)?
+        .insert(hash.as_bytes(), &slices.to_be_bytes())?;
+    Ok(())
+}
+
+/// Slice count of a chunk, if it's already stored
+pub fn get_chunk_slices(hash: &blake3::Hash) -> Result<Option<u64>> {
+    Ok(DB_KV
+        .open_tree(CHUNK_TREE)?
+        .get(hash.as_bytes())?
+        .map(|slices| u64::from_be_bytes(fix_slice::<8>(&slices))))
+}
+
+pub fn remove_chunk(hash: &blake3::Hash) -> Result<()> {
+    DB_KV.open_tree(CHUNK_TREE)?.remove(hash.as_bytes())?;
+    Ok(())
+}
+
+/// ## Peers
+
+/// ### Peer Info struct
+pub struct PeerInfo {
+    pub tor_v3: String, // Primary key
+    pub label: Option<String>,
+    pub date_created: DateTime<Utc>,
+    pub client: bool,     // Storage client this node provides storage for
+    pub provider: bool,   // Storage provider this node stores data on
+    pub cap: Option<u64>, // Storage cap for a client, in megabytes
+    pub bytes_used: u64,  // Encoded bytes stored by a client
+}
+
+pub async fn insert_peer(peer: PeerInfo) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   INSERT INTO peers (
+                    tor_v3,
+                    label,
+                    date_created,
+                    client,
+                    provider,
+                    cap,
+                    bytes_used
+                ) VALUES (
+                    :tor_v3,
+                    :label,
+                    :date_created,
+                    :client,
+                    :provider,
+                    :cap,
+                    :bytes_used
+                )",
+    )?;
+
+    stmt.execute(named_params! {
+        ":tor_v3": peer.tor_v3,
+        ":label": peer.label,
+        ":date_created": peer.date_created.timestamp_millis(),
+        ":client": peer.client,
+        ":provider": peer.provider,
+        ":cap": peer.cap,
+        ":bytes_used": peer.bytes_used,
+    })?;
+
+    Ok(())
+}
+
+/// Records a storage provider a channel was opened to. Opening another channel to the same provider keeps its row.
+pub async fn upsert_provider(tor_v3: &str) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   INSERT INTO peers (
+                    tor_v3,
+                    date_created,
+                    client,
+                    provider
+                ) VALUES (
+                    :tor_v3,
+                    :date_created,
+                    FALSE,
+                    TRUE
+                )
+                ON CONFLICT (tor_v3) DO UPDATE SET provider = TRUE",
+    )?;
+
+    stmt.execute(named_params! {
+        ":tor_v3": tor_v3,
+        ":date_created": Utc::now().timestamp_millis(),
+    })?;
+
+    Ok(())
+}
+
+fn peer_from_row(row: &rusqlite::Row) -> rusqlite::Result<PeerInfo> {
+    let date_created: i64 = row.get("date_created")?;
+
+    Ok(PeerInfo {
+        tor_v3: row.get("tor_v3")?,
+        label: row.get("label")?,
+        date_created: datetime_from_millis(date_created),
+        client: row.get("client")?,
+        provider: row.get("provider")?,
+        cap: row.get("cap")?,
+        bytes_used: row.get("bytes_used")?,
+    })
+}
+
+pub async fn get_peer(tor_v3: &str) -> Result<Option<PeerInfo>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM peers
+                WHERE tor_v3 = :tor_v3",
+    )?;
+
+    Ok(stmt
+        .query_row(named_params! { ":tor_v3": tor_v3 }, peer_from_row)
+        .optional()?)
+}
+
+/// Returned when storing more data for a client would go over the cap it was issued with
+#[derive(Debug)]
+pub struct CapExceeded {
+    pub cap: u64,    // Client's cap, in bytes
+    pub used: u64,   // Bytes already stored for the client
+    pub needed: u64, // Bytes the client tried to store
+}
+
+impl fmt::Display for CapExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Storage cap exceeded: {} of {} used, {} more requested",
+            human_bytes(self.used as f64),
+            human_bytes(self.cap as f64),
+            human_bytes(self.needed as f64),
+        )
+    }
+}
+
+impl std::error::Error for CapExceeded {}
+
+/// Counts bytes against a client's storage cap, failing with `CapExceeded` if they'd go over it
+pub async fn reserve_client_storage(tor_v3: &str, bytes: u64) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+
+    let (cap, used): (Option<u64>, u64) = conn
+        .prepare_cached(
+            "   SELECT cap, bytes_used
+                FROM peers
+                WHERE tor_v3 = :tor_v3 AND client = TRUE",
+        )?
+        .query_row(named_params! { ":tor_v3": tor_v3 }, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?
+        .ok_or_else(|| anyhow!("{} is not an authorized storage client", tor_v3))?;
+
+    if let Some(cap) = cap.map(|cap| cap.saturating_mul(MEGABYTE)) {
+        if used + bytes > cap {
+            return Err(CapExceeded {
+                cap,
+                used,
+                needed: bytes,
+            }
+            .into());
+        }
+    }
+
+    conn.prepare_cached(
+        "   UPDATE peers
+            SET bytes_used = bytes_used + :bytes
+            WHERE tor_v3 = :tor_v3",
+    )?
+    .execute(named_params! { ":tor_v3": tor_v3, ":bytes": bytes })?;
+
+    Ok(())
+}
+
+/// Gives bytes back to a client's storage cap once its files are deleted
+pub async fn release_client_storage(tor_v3: &str, bytes: u64) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+
+    conn.prepare_cached(
+        "   UPDATE peers
+            SET bytes_used = MAX(bytes_used - :bytes, 0)
+            WHERE tor_v3 = :tor_v3",
+    )?
+    .execute(named_params! { ":tor_v3": tor_v3, ":bytes": bytes })?;
+
+    Ok(())
+}
+
+/// Number of storage clients and storage providers this node knows of
+pub async fn get_peer_counts() -> Result<(u64, u64)> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT COALESCE(SUM(client), 0), COALESCE(SUM(provider), 0)
+                FROM peers",
+    )?;
+
+    Ok(stmt.query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?)
+}
+
+/// ## Verifications
+
+/// ### Verification Record struct
+pub struct VerificationRecord {
+    pub date_verified: DateTime<Utc>,
+    pub peer: Option<String>, // Storage provider challenged, or none if the file is on this node's volumes
+    pub blake3_hash: String,
+    pub slice_index: u64,
+    pub passed: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+pub async fn insert_verification(verification: &VerificationRecord) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   INSERT INTO verifications (
+                    date_verified,
+                    peer,
+                    blake3_hash,
+                    slice_index,
+                    passed,
+                    latency_ms,
+                    error
+                ) VALUES (
+                    :date_verified,
+                    :peer,
+                    :blake3_hash,
+                    :slice_index,
+                    :passed,
+                    :latency_ms,
+                    :error
+                )",
+    )?;
+
+    stmt.execute(named_params! {
+        ":date_verified": verification.date_verified.timestamp_millis(),
+        ":peer": verification.peer,
+        ":blake3_hash": verification.blake3_hash,
+        ":slice_index": verification.slice_index,
+        ":passed": verification.passed,
+        ":latency_ms": verification.latency_ms,
+        ":error": verification.error,
+    })?;
+
+    Ok(())
+}
+
+/// Most recent verifications, newest first
+pub async fn get_verifications(limit: usize) -> Result<Vec<VerificationRecord>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT *
+                FROM verifications
+                ORDER BY date_verified DESC, rowid DESC
+                LIMIT :limit",
+    )?;
+
+    let verifications = stmt
+        .query_map(named_params! { ":limit": limit as i64 }, |row| {
+            let date_verified: i64 = row.get("date_verified")?;
+
+            Ok(VerificationRecord {
+                date_verified: datetime_from_millis(date_verified),
+                peer: row.get("peer")?,
+                blake3_hash: row.get("blake3_hash")?,
+                slice_index: row.get("slice_index")?,
+                passed: row.get("passed")?,
+                latency_ms: row.get("latency_ms")?,
+                error: row.get("error")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(verifications)
+}
+
+/// Slices a storage provider has been challenged for since a point in time
+pub async fn count_verifications_since(peer: &str, since: DateTime<Utc>) -> Result<u64> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT COUNT(*)
+                FROM verifications
+                WHERE peer = :peer AND date_verified >= :since",
+    )?;
+
+    Ok(stmt.query_row(
+        named_params! { ":peer": peer, ":since": since.timestamp_millis() },
+        |row| row.get(0),
+    )?)
+}
+
+/// ### How a storage provider, or this node's volumes, have held up to verification
+pub struct TrackRecord {
+    pub peer: Option<String>,
+    pub checks: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64, // Failures since the last slice that passed
+    pub average_latency_ms: u64,
+    pub last_passed: Option<DateTime<Utc>>,
+    pub last_failed: Option<DateTime<Utc>>,
+}
+
+pub async fn get_track_records() -> Result<Vec<TrackRecord>> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT
+                    peer,
+                    COUNT(*) AS checks,
+                    SUM(NOT passed) AS failures,
+                    CAST(AVG(latency_ms) AS INTEGER) AS average_latency_ms,
+                    MAX(CASE WHEN passed THEN date_verified END) AS last_passed,
+                    MAX(CASE WHEN NOT passed THEN date_verified END) AS last_failed,
+                    SUM(
+                        NOT passed AND date_verified > COALESCE(
+                            (SELECT MAX(date_verified) FROM verifications AS v
+                                WHERE v.peer IS verifications.peer AND v.passed),
+                            -1
+                        )
+                    ) AS consecutive_failures
+                FROM verifications
+                GROUP BY peer
+                ORDER BY peer",
+    )?;
+
+    let records = stmt
+        .query_map([], |row| {
+            let last_passed: Option<i64> = row.get("last_passed")?;
+            let last_failed: Option<i64> = row.get("last_failed")?;
+
+            Ok(TrackRecord {
+                peer: row.get("peer")?,
+                checks: row.get("checks")?,
+                failures: row.get("failures")?,
+                consecutive_failures: row.get("consecutive_failures")?,
+                average_latency_ms: row.get("average_latency_ms")?,
+                last_passed: last_passed.map(datetime_from_millis),
+                last_failed: last_failed.map(datetime_from_millis),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(records)
+}
+
+/// ## Blobs
+
+/// ### Records an encoded file stored for a storage client, so it counts against the volume it was placed on
+pub async fn insert_blob(client: &str, blake3_hash: &str, volume: &Path, bytes: u64) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   INSERT OR REPLACE INTO blobs (
+                    client,
+                    blake3_hash,
+                    volume,
+                    bytes
+                ) VALUES (
+                    :client,
+                    :blake3_hash,
+                    :volume,
+                    :bytes
+                )",
+    )?;
+
+    stmt.execute(named_params! {
+        ":client": client,
+        ":blake3_hash": blake3_hash,
+        ":volume": volume.to_string_lossy(),
+        ":bytes": bytes,
+    })?;
+
+    Ok(())
+}
+
+/// ### Whether a storage client already stored an encoded file
+pub async fn contains_blob(client: &str, blake3_hash: &str) -> Result<bool> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   SELECT 1
+                FROM blobs
+                WHERE client = :client AND blake3_hash = :blake3_hash",
+    )?;
+
+    Ok(stmt.exists(named_params! {
+        ":client": client,
+        ":blake3_hash": blake3_hash,
+    })?)
+}
+
+pub async fn remove_blob(client: &str, blake3_hash: &str) -> Result<()> {
+    let conn = DB_SQL.lock().await;
+    let mut stmt = conn.prepare_cached(
+        "   DELETE FROM blobs
+                WHERE client = :client AND blake3_hash = :blake3_hash",
+    )?;
+
+    stmt.execute(named_params! {
+        ":client": client,
+        ":blake3_hash": blake3_hash,
+    })?;
+
+    Ok(())
+}
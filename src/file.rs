@@ -1,26 +1,55 @@
-#![allow(dead_code)]
+#![allow(
+    dead_code,
+    clippy::needless_borrows_for_generic_args,
+    clippy::unnecessary_to_owned
+)]
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     env::current_dir,
     fs::File,
+    io::Read,
+    mem::take,
     path::{Path, PathBuf},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use chrono::DateTime;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use human_bytes::human_bytes;
-use log::info;
+use log::{info, warn};
+use rand::{seq::SliceRandom, Rng};
+#[cfg(feature = "rayon")]
+use rayon::ThreadPoolBuilder;
+use tokio::{fs::metadata, sync::oneshot};
+use torut::onion::OnionAddressV3;
 use walkdir::WalkDir;
 
 use crate::{
+    backend::{Staged, Storage},
+    config::{get_cfg, CompressionCfg, SysCfg},
+    crypt::encrypted_len,
     db::{
-        contains_hash, flush_kv, get_files, get_hashes_by_prefix, get_max_slice, insert_file,
-        insert_hash, mark_as_dropped, remove_hash, upsert_path, FileInfo, USR_CONFIG,
+        cache_hash, contains_hash, flush_kv, get_cached_hash, get_chunk_refs, get_chunk_slices,
+        get_file, get_files, get_hashes_by_prefix, get_latest_revision, get_manifest,
+        get_next_slice, get_revisions_by_prefix, get_verification_sample, insert_chunk,
+        insert_dictionary, insert_files, insert_hash, insert_verification, mark_as_removed,
+        mark_as_verified, release_manifest, remove_chunk, remove_hash, remove_path, revert_path,
+        upsert_path, ChunkInfo, FileInfo, FileStat, Manifest, SampledFile, VerificationRecord,
+        USR_CONFIG,
+    },
+    hash::{
+        blocking, compress_bound, encoded_len, extract, extract_chunk, hash_and_encode_chunks_of,
+        hash_and_encode_file, hash_file, infer_mime_type,
+        train_dictionary as train_dictionary_from, verify_slice, ChunkedFileInfo, Compressed,
+        FileEncryption, HashedChunk, HashedFileInfo, SLICE_LEN,
     },
-    hash::{encode, extract, hash_file, infer_mime_type, EncodedFileInfo},
 };
 
+const DICTIONARY_SAMPLE_FILES: usize = 1000;
+const DICTIONARY_SAMPLE_LEN: u64 = 128 * 1024;
+const DICTIONARY_MAX_LEN: usize = 110 * 1024;
+
 pub struct Offset(u64);
 
 impl Offset {
@@ -34,12 +63,16 @@ impl Offset {
     }
 }
 
-pub fn walk_dir(path: &Path, prefix: &str) -> Result<BTreeMap<PathBuf, blake3::Hash>> {
-    let start = Instant::now();
+/// Paths of files under a path, in order, without reading them
+pub fn find_files(path: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
     let cwd = current_dir()?.to_string_lossy().to_string();
-    let mut map = BTreeMap::new();
+    let mut paths = vec![];
 
-    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+    for entry in WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
         if entry.file_type().is_file() {
             let entry_path = entry.into_path();
 
@@ -49,83 +82,254 @@ pub fn walk_dir(path: &Path, prefix: &str) -> Result<BTreeMap<PathBuf, blake3::H
                 .replace(&cwd, "")
                 .starts_with(&prefix)
             {
-                let blake3_hash = hash_file(&entry_path, &USR_CONFIG.hash_key.to_owned())?;
-
-                map.insert(entry_path, blake3_hash);
+                paths.push(entry_path);
             }
         }
     }
 
+    Ok(paths)
+}
+
+/// How many files a scan found unchanged since they were last hashed, and how many it read to hash
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanStats {
+    pub cached: usize,
+    pub hashed: usize,
+}
+
+/// Files modified this recently could change again without their modification time changing, so their hashes aren't cached
+const SCAN_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Caches a file's hash along with how it looked before it was read, unless it was modified too recently to be trusted
+fn cache_scan(file_path: &Path, stat: &FileStat, hash: &blake3::Hash) -> Result<()> {
+    let modified = UNIX_EPOCH + Duration::from_nanos(stat.modified.try_into()?);
+
+    if SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age >= SCAN_SETTLE_TIME)
+    {
+        cache_hash(&file_path.to_string_lossy(), stat, hash)?;
+    }
+
+    Ok(())
+}
+
+/// Keyed hashes of files under a path. Files that haven't changed since they were last hashed aren't read again, unless `rescan` is set.
+pub fn walk_dir(
+    path: &Path,
+    prefix: &str,
+    rescan: bool,
+) -> Result<(BTreeMap<PathBuf, blake3::Hash>, ScanStats)> {
+    let start = Instant::now();
+    let mut map = BTreeMap::new();
+    let mut stats = ScanStats::default();
+
+    for entry_path in find_files(path, prefix)? {
+        let stat = FileStat::from_metadata(&entry_path.metadata()?)?;
+        let cached = if rescan {
+            None
+        } else {
+            get_cached_hash(&entry_path.to_string_lossy(), &stat)?
+        };
+
+        let blake3_hash = match cached {
+            Some(blake3_hash) => {
+                stats.cached += 1;
+                blake3_hash
+            }
+            None => {
+                let blake3_hash = hash_file(&entry_path, &USR_CONFIG.hash_key.to_owned())?;
+                cache_scan(&entry_path, &stat, &blake3_hash)?;
+                stats.hashed += 1;
+                blake3_hash
+            }
+        };
+
+        map.insert(entry_path, blake3_hash);
+    }
+
     info!(
-        "{} files found locally in {:.2?}",
+        "{} files found locally in {:.2?} ({} unchanged since they were last scanned, {} hashed)",
         map.len(),
-        start.elapsed()
+        start.elapsed(),
+        stats.cached,
+        stats.hashed
     );
 
-    Ok(map)
+    Ok((map, stats))
+}
+
+/// Runs `walk_dir` on the blocking thread pool, since it may read every file under the path
+pub async fn scan_dir(
+    path: &Path,
+    prefix: &str,
+    rescan: bool,
+) -> Result<(BTreeMap<PathBuf, blake3::Hash>, ScanStats)> {
+    let (path, prefix) = (path.to_owned(), prefix.to_owned());
+    blocking(move || walk_dir(&path, &prefix, rescan)).await
 }
 
 /// Uploads all files under a path to storage channels.
-pub async fn upload_path(prefix: &str, data_dir: &Path) -> Result<()> {
+/// Each file is hashed as it's encoded, so it's only read once, and the encoding is discarded if the file was already uploaded.
+/// Files that haven't changed since they were last scanned aren't read at all, unless `rescan` is set.
+///
+/// Files are hashed and encoded by a rayon pool of workers, then stored and recorded in the order they were found,
+/// with records inserted in batches. Files are split into chunks that are stored by themselves if chunking is enabled.
+/// Without the `rayon` feature, files are hashed and encoded one at a time on tokio's blocking thread pool.
+pub async fn upload_path(prefix: &str, data_dir: &Path, rescan: bool) -> Result<ScanStats> {
     let start = Instant::now();
-    let files = walk_dir(data_dir, prefix)?;
+    let cfg = Arc::new(get_cfg().await?);
+    let files = {
+        let (data_dir, prefix) = (data_dir.to_owned(), prefix.to_owned());
+        blocking(move || find_files(&data_dir, &prefix)).await?
+    };
     let files_len = files.len();
+    let workers = cfg.upload.worker_count();
+    #[cfg(feature = "rayon")]
+    let pool = ThreadPoolBuilder::new().num_threads(workers).build()?;
+    let mut stats = ScanStats::default();
     let mut bytes_read = 0;
     let mut bytes_written = 0;
+    let mut volumes_written: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    let mut bytes_sent = 0;
 
-    for (file_path, blake3_hash) in files {
-        let blake3_bytes = blake3_hash.as_bytes();
+    let mut storage = Storage::open().await?;
+    let mut next_slice = get_next_slice().await?;
+    let mut batch = vec![];
+    let mut batch_staged = vec![];
+    let mut pending = VecDeque::new();
 
-        if contains_hash(blake3_bytes)? {
-            continue;
-        } else {
-            insert_hash(blake3_bytes)?;
-        }
+    let uploaded: Result<()> = async {
+        let mut files = files.into_iter();
 
-        let EncodedFileInfo {
-            bao_hash,
-            read,
-            written,
-        } = encode(&file_path, &blake3_hash.to_hex().to_string()).await?;
+        loop {
+            // Workers are kept busy until there are no files left to hand them
+            while pending.len() < workers {
+                let file_path = match files.next() {
+                    Some(file_path) => file_path,
+                    None => break,
+                };
 
-        let parent_rev = upsert_path(&file_path.to_string_lossy(), blake3_bytes)?;
-        let mime_type = infer_mime_type(&file_path)?;
-        let metadata = File::open(&file_path)?.metadata()?;
+                let stat = FileStat::from_metadata(&metadata(&file_path).await?)?;
+                let cached = if rescan {
+                    None
+                } else {
+                    get_cached_hash(&file_path.to_string_lossy(), &stat)?
+                };
 
-        // Relative path to Forage Data dir
-        let path = file_path.strip_prefix(&data_dir)?.to_path_buf();
+                if let Some(blake3_hash) = cached {
+                    if contains_hash(blake3_hash.as_bytes())? {
+                        stats.cached += 1;
+                        continue;
+                    }
+                }
+                stats.hashed += 1;
 
-        let min_slice = get_max_slice().await?;
-        let max_slice = min_slice + (read as f64 / 1024.0).ceil() as u64;
+                // Fails with `VolumeFull` before anything is written if no volume has room for the file.
+                // Room is set aside for it until it's recorded, so files encoded at the same time can't overfill a volume.
+                // Contents that don't compress come out larger than they went in
+                let compressed_len = if cfg.compression.enabled {
+                    compress_bound(stat.size)
+                } else {
+                    stat.size
+                };
+                let stored_len = if cfg.encrypt {
+                    encrypted_len(compressed_len)
+                } else {
+                    compressed_len
+                };
+                let staged = Staged::new(
+                    storage
+                        .for_new()
+                        .stage(&staging_name(), encoded_len(stored_len))
+                        .await?,
+                );
 
-        let file_info = FileInfo {
-            blake3_hash,
-            bao_hash,
-            bytes_read: read,
-            bytes_written: written,
-            min_slice,
-            max_slice,
-            path,
-            parent_rev,
-            mime_type,
-            date_created: DateTime::from(metadata.created()?),
-            date_modified: DateTime::from(metadata.modified()?),
-            date_accessed: DateTime::from(metadata.accessed()?),
-            dropped: false,
-            removed: false,
-        };
+                let (sender, worker) = oneshot::channel();
+                let encode = {
+                    let (file_path, staged, cfg) =
+                        (file_path.clone(), staged.path().to_owned(), cfg.clone());
+                    move || {
+                        let _ = sender.send(encode_upload(&file_path, stat, cached, &staged, &cfg));
+                    }
+                };
+                #[cfg(feature = "rayon")]
+                pool.spawn(encode);
+                #[cfg(not(feature = "rayon"))]
+                tokio::task::spawn_blocking(encode);
+                pending.push_back((file_path, staged, worker));
+            }
+
+            let (file_path, staged, worker) = match pending.pop_front() {
+                Some(next) => next,
+                None => break,
+            };
+            let staged_path = staged.path().to_owned();
+
+            let stored = store_upload(
+                &mut storage,
+                data_dir,
+                &file_path,
+                staged,
+                worker.await??,
+                &mut next_slice,
+            )
+            .await?;
 
-        insert_file(file_info).await?;
+            let (file_info, manifest) = match stored {
+                Some(uploaded) => uploaded,
+                None => {
+                    storage.for_new().unstage(&staged_path);
+                    continue;
+                }
+            };
 
-        if let Some(parent_hash) = parent_rev {
-            mark_as_dropped(parent_hash).await?;
+            bytes_read += file_info.bytes_read;
+            bytes_written += file_info.bytes_written;
+
+            // Chunks are placed by themselves, so a chunked file can be spread across volumes
+            let placed = match &manifest {
+                Some(manifest) => manifest
+                    .new_chunks
+                    .iter()
+                    .map(|chunk| (chunk.volume.clone(), chunk.bytes_written))
+                    .collect(),
+                None => vec![(file_info.volume.clone(), file_info.bytes_written)],
+            };
+            for (volume, written) in placed {
+                match volume {
+                    Some(volume) => *volumes_written.entry(volume).or_default() += written,
+                    None => bytes_sent += written,
+                }
+            }
+
+            batch.push((file_info, manifest));
+            batch_staged.push(staged_path);
+            if batch.len() >= cfg.upload.batch_size {
+                record_batch(take(&mut batch), data_dir).await?;
+                for staged in take(&mut batch_staged) {
+                    storage.for_new().unstage(&staged);
+                }
+            }
         }
 
-        bytes_read += read;
-        bytes_written += written;
+        Ok(())
     }
+    .await;
 
+    // Files still being encoded when one failed are never stored.
+    // Their staged encodings are removed as they're dropped, once their workers are done writing them.
+    for (_, staged, worker) in pending {
+        let _ = worker.await;
+        drop(staged);
+    }
+
+    // Files already stored are recorded even if a later one failed, since their hashes are known to be uploaded
+    if !batch.is_empty() {
+        record_batch(batch, data_dir).await?;
+    }
     flush_kv()?;
+    uploaded?;
 
     info!(
         "{} bytes read. {} files processed in {:.2?}. {} bytes written.",
@@ -134,6 +338,26 @@ pub async fn upload_path(prefix: &str, data_dir: &Path) -> Result<()> {
         start.elapsed(),
         human_bytes(bytes_written as f64),
     );
+    info!(
+        "{} files unchanged since they were last scanned, {} read.",
+        stats.cached, stats.hashed
+    );
+
+    for (volume, written) in volumes_written {
+        info!(
+            "{} written to {}.",
+            human_bytes(written as f64),
+            volume.to_string_lossy()
+        );
+    }
+
+    if let Some(provider) = storage.provider() {
+        info!(
+            "{} sent to storage provider {}.",
+            human_bytes(bytes_sent as f64),
+            provider
+        );
+    }
 
     if bytes_read > 0 {
         info!(
@@ -142,11 +366,287 @@ pub async fn upload_path(prefix: &str, data_dir: &Path) -> Result<()> {
         );
     }
 
-    Ok(())
+    Ok(stats)
+}
+
+/// Records a batch of uploaded files.
+/// If that fails, their hashes and the chunks first stored with them are forgotten, and their paths point back at their previous revisions,
+/// so later uploads store them again instead of taking them for duplicates of files that were never recorded.
+async fn record_batch(batch: Vec<(FileInfo, Option<Manifest>)>, data_dir: &Path) -> Result<()> {
+    let new_chunks: Vec<blake3::Hash> = batch
+        .iter()
+        .filter_map(|(_, manifest)| manifest.as_ref())
+        .flat_map(|manifest| manifest.new_chunks.iter().map(|chunk| chunk.blake3_hash))
+        .collect();
+    let uploaded: Vec<(blake3::Hash, PathBuf, Option<blake3::Hash>)> = batch
+        .iter()
+        .map(|(file, _)| (file.blake3_hash, data_dir.join(&file.path), file.parent_rev))
+        .collect();
+
+    let recorded = insert_files(batch).await;
+    if recorded.is_err() {
+        for chunk_hash in new_chunks {
+            let _ = remove_chunk(&chunk_hash);
+        }
+        for (blake3_hash, file_path, parent_rev) in uploaded {
+            let _ = remove_hash(blake3_hash);
+            let _ = revert_path(&file_path.to_string_lossy(), &blake3_hash, parent_rev);
+        }
+    }
+
+    recorded
+}
+
+/// A file hashed and encoded by an upload worker, either whole or in chunks
+enum EncodedUpload {
+    Whole(HashedFileInfo),
+    Chunked(ChunkedFileInfo),
+}
+
+/// Upload worker, run off the async runtime: hashes and encodes a file to where it was staged,
+/// or splits it into chunks encoded next to it if it's large enough to be chunked.
+fn encode_upload(
+    file_path: &Path,
+    stat: FileStat,
+    cached: Option<blake3::Hash>,
+    staged: &Path,
+    cfg: &SysCfg,
+) -> Result<EncodedUpload> {
+    let hash_key = &USR_CONFIG.hash_key;
+
+    // Chunks are encrypted with keys derived from their own hashes, so chunked files are only read once either way
+    if cfg.chunking.chunks(stat.size) {
+        let chunked = hash_and_encode_chunks_of(
+            file_path,
+            hash_key,
+            staged,
+            &cfg.chunking,
+            &cfg.compression,
+            cfg.encrypt,
+        )?;
+        cache_scan(file_path, &stat, &chunked.blake3_hash)?;
+        return Ok(EncodedUpload::Chunked(chunked));
+    }
+
+    // Encryption keys are derived from file hashes, so a cached hash lets a file be encrypted as it's hashed
+    let encryption = match (cfg.encrypt, cached) {
+        (false, _) => FileEncryption::Off,
+        (true, Some(blake3_hash)) => FileEncryption::ByKnownHash(blake3_hash),
+        (true, None) => FileEncryption::ByHash,
+    };
+    let hashed = hash_and_encode_file(file_path, hash_key, staged, &cfg.compression, encryption)?;
+    cache_scan(file_path, &stat, &hashed.blake3_hash)?;
+
+    Ok(EncodedUpload::Whole(hashed))
+}
+
+/// Stores an encoded file and describes it to be recorded, unless an earlier file had the same contents.
+/// Chunked files are described along with their manifests, and only the chunks that weren't stored yet are stored.
+/// Slice ranges are handed out from `next_slice`, since files waiting in a batch aren't recorded yet.
+async fn store_upload(
+    storage: &mut Storage,
+    data_dir: &Path,
+    file_path: &Path,
+    staged: Staged,
+    encoded: EncodedUpload,
+    next_slice: &mut u64,
+) -> Result<Option<(FileInfo, Option<Manifest>)>> {
+    let blake3_hash = match &encoded {
+        EncodedUpload::Whole(hashed) => hashed.blake3_hash,
+        EncodedUpload::Chunked(chunked) => chunked.blake3_hash,
+    };
+    let blake3_bytes = blake3_hash.as_bytes();
+
+    // Its encoding is removed as it's dropped
+    if contains_hash(blake3_bytes)? {
+        return Ok(None);
+    }
+
+    let StoredUpload {
+        bao_hash,
+        bytes_read,
+        bytes_written,
+        slices,
+        mime_type,
+        volume,
+        compressed,
+        encrypted,
+        manifest,
+    } = match encoded {
+        EncodedUpload::Whole(HashedFileInfo {
+            mime_type, encoded, ..
+        }) => StoredUpload {
+            volume: {
+                let volume = storage
+                    .for_new()
+                    .put(&blake3_hash.to_hex(), staged.path())
+                    .await?;
+                staged.stored();
+                volume
+            },
+            bao_hash: encoded.bao_hash,
+            bytes_read: encoded.read,
+            bytes_written: encoded.written,
+            // Even an empty file has a slice that can be verified
+            slices: encoded.stored.div_ceil(SLICE_LEN).max(1),
+            mime_type,
+            compressed: encoded.compressed,
+            encrypted: encoded.encrypted,
+            manifest: None,
+        },
+        EncodedUpload::Chunked(chunked) => store_chunks(storage, chunked).await?,
+    };
+
+    insert_hash(blake3_bytes)?;
+
+    let parent_rev = upsert_path(&file_path.to_string_lossy(), blake3_bytes)?;
+    let metadata = metadata(file_path).await?;
+
+    // Relative path to Forage Data dir
+    let path = file_path.strip_prefix(&data_dir)?.to_path_buf();
+
+    let min_slice = *next_slice;
+    let max_slice = min_slice + slices;
+    *next_slice = max_slice;
+
+    let file_info = FileInfo {
+        blake3_hash,
+        bao_hash,
+        bytes_read,
+        bytes_written,
+        min_slice,
+        max_slice,
+        path,
+        parent_rev,
+        mime_type,
+        date_created: DateTime::from(metadata.created()?),
+        date_modified: DateTime::from(metadata.modified()?),
+        date_accessed: DateTime::from(metadata.accessed()?),
+        dropped: false,
+        removed: false,
+        volume,
+        compressed,
+        encrypted,
+        chunked: manifest.is_some(),
+    };
+
+    Ok(Some((file_info, manifest)))
+}
+
+/// How an uploaded file was stored, to be described along with where it was uploaded from
+struct StoredUpload {
+    bao_hash: bao::Hash,
+    bytes_read: u64,
+    bytes_written: u64,
+    slices: u64,
+    mime_type: String,
+    volume: Option<PathBuf>,
+    compressed: Option<Compressed>,
+    encrypted: bool,
+    manifest: Option<Manifest>,
+}
+
+/// Stores the chunks of a file that weren't stored yet. Its slices are those of all its chunks, in order.
+/// A chunk another worker stored since this one was encoded is discarded, and the stored one is used.
+async fn store_chunks(storage: &mut Storage, chunked: ChunkedFileInfo) -> Result<StoredUpload> {
+    let ChunkedFileInfo {
+        mime_type,
+        bytes_read,
+        chunks,
+        ..
+    } = chunked;
+    let mut manifest = Manifest {
+        chunks: Vec::with_capacity(chunks.len()),
+        new_chunks: vec![],
+    };
+    let mut slices = 0;
+
+    let stored: Result<()> = async {
+        for HashedChunk {
+            blake3_hash,
+            slices: chunk_slices,
+            encoded,
+        } in chunks
+        {
+            manifest.chunks.push(blake3_hash);
+
+            let (staged, encoded) = match encoded {
+                Some(encoded) => encoded,
+                None => {
+                    slices += chunk_slices;
+                    continue;
+                }
+            };
+
+            if let Some(stored_slices) = get_chunk_slices(&blake3_hash)? {
+                slices += stored_slices;
+                continue;
+            }
+
+            let volume = storage
+                .for_new()
+                .put(&blake3_hash.to_hex(), staged.path())
+                .await?;
+            staged.stored();
+            insert_chunk(&blake3_hash, chunk_slices)?;
+            slices += chunk_slices;
+
+            manifest.new_chunks.push(ChunkInfo {
+                blake3_hash,
+                bao_hash: encoded.bao_hash,
+                bytes_read: encoded.read,
+                bytes_written: encoded.written,
+                slices: chunk_slices,
+                volume,
+                compressed: encoded.compressed,
+                encrypted: encoded.encrypted,
+            });
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Chunks stored before one failed are forgotten, since the file they're part of is never recorded.
+    // Those that weren't stored are removed as they're dropped.
+    if stored.is_err() {
+        for chunk in &manifest.new_chunks {
+            let _ = remove_chunk(&chunk.blake3_hash);
+        }
+    }
+    stored?;
+
+    // Chunked files have no encoding of their own, so their bao hash is the hash of their manifest
+    let mut hasher = blake3::Hasher::new();
+    for chunk_hash in &manifest.chunks {
+        hasher.update(chunk_hash.as_bytes());
+    }
+
+    Ok(StoredUpload {
+        bao_hash: bao::Hash::from(*hasher.finalize().as_bytes()),
+        bytes_read,
+        bytes_written: manifest.new_chunks.iter().map(|c| c.bytes_written).sum(),
+        slices,
+        mime_type,
+        volume: None,
+        // Chunks record how they were compressed and encrypted themselves
+        compressed: None,
+        encrypted: false,
+        manifest: Some(manifest),
+    })
+}
+
+/// Temporary name a file is encoded under until its hash is known
+fn staging_name() -> String {
+    format!("staging-{:016x}", rand::random::<u64>())
 }
 
-pub async fn download_by_prefix(prefix: &str, data_dir: &Path) -> Result<Vec<PathBuf>> {
-    let local_files = walk_dir(data_dir, prefix)?;
+pub async fn download_by_prefix(
+    prefix: &str,
+    data_dir: &Path,
+    rescan: bool,
+) -> Result<Vec<PathBuf>> {
+    let (local_files, _) = scan_dir(data_dir, prefix, rescan).await?;
 
     let local_hash_set = local_files
         .iter()
@@ -163,29 +663,627 @@ pub async fn download_by_prefix(prefix: &str, data_dir: &Path) -> Result<Vec<Pat
     };
 
     let mut results = vec![];
+    let mut storage = Storage::open().await?;
 
     for file in stored_files {
-        extract(
-            &data_dir.join(&file.path),
-            &file.bao_hash,
-            file.blake3_hash.to_hex().as_str(),
-            file.bytes_read,
-        )
-        .await?;
-
+        restore(&mut storage, &file, data_dir).await?;
         results.push(file.path);
     }
 
     Ok(results)
 }
 
-/// Verify oldest file, newest file, and three random files inbetween
-pub async fn verify_data() -> Result<(String, u64, u64)> {
-    todo!();
+/// Retrieves the revisions of files under a prefix that were current at a point in time.
+/// A revision is current from when its file was last modified, until the next revision.
+pub async fn download_at(
+    prefix: &str,
+    data_dir: &Path,
+    at: DateTime<Utc>,
+    rescan: bool,
+) -> Result<Vec<PathBuf>> {
+    let local_hashes: HashSet<blake3::Hash> = scan_dir(data_dir, prefix, rescan)
+        .await?
+        .0
+        .into_values()
+        .collect();
+
+    // Revisions are ordered oldest first, so later ones replace earlier ones
+    let mut current = BTreeMap::new();
+    for revision in get_revisions_by_prefix(prefix.trim_start_matches('/')).await? {
+        if revision.date_modified <= at {
+            current.insert(revision.path.clone(), revision);
+        }
+    }
+
+    let mut results = vec![];
+    let mut storage = Storage::open().await?;
+
+    for file in current.into_values() {
+        if !local_hashes.contains(&file.blake3_hash) {
+            restore(&mut storage, &file, data_dir).await?;
+            results.push(file.path);
+        }
+    }
+
+    Ok(results)
+}
+
+/// Retrieves a specific revision of a file to its path, replacing whatever is there
+pub async fn download_revision(blake3_hash: &blake3::Hash, data_dir: &Path) -> Result<PathBuf> {
+    let file = get_file(blake3_hash)
+        .await?
+        .ok_or_else(|| anyhow!("No revision {} was uploaded", blake3_hash))?;
+
+    if file.removed {
+        return Err(anyhow!(
+            "Revision {} of {} was removed",
+            blake3_hash,
+            file.path.to_string_lossy()
+        ));
+    }
+
+    let mut storage = Storage::open().await?;
+    restore(&mut storage, &file, data_dir).await?;
+
+    Ok(file.path)
+}
+
+/// Revisions of a file, newest first, following each revision back to the one it replaced
+pub async fn history(path: &Path) -> Result<Vec<FileInfo>> {
+    let mut revisions: Vec<FileInfo> = vec![];
+    let mut next = get_latest_revision(path).await?;
+
+    while let Some(revision) = next {
+        // Content that was uploaded before under another path can link back into a longer chain
+        if revisions
+            .iter()
+            .any(|seen| seen.blake3_hash == revision.blake3_hash)
+        {
+            break;
+        }
+
+        next = match revision.parent_rev {
+            Some(parent_rev) => get_file(&parent_rev).await?,
+            None => None,
+        };
+        revisions.push(revision);
+    }
+
+    Ok(revisions)
+}
+
+/// Revisions pruned, or that would be pruned on a dry run
+pub struct PruneReport {
+    pub pruned: Vec<FileInfo>,
+    pub bytes_reclaimed: u64, // Encoded bytes freed on volumes and storage providers
+}
+
+/// Removes older revisions of files under a prefix that the configured retention rules don't keep
+pub async fn prune(prefix: &str, dry_run: bool) -> Result<PruneReport> {
+    let SysCfg {
+        forage_data_dir: data_dir,
+        retention,
+        ..
+    } = get_cfg().await?;
+
+    if !retention.is_set() {
+        return Err(anyhow!(
+            "No retention rules are set in the [retention] section of cfg.toml, so every older revision would be pruned"
+        ));
+    }
+
+    let now = Utc::now();
+    let mut by_path: BTreeMap<PathBuf, Vec<FileInfo>> = BTreeMap::new();
+
+    for revision in get_revisions_by_prefix(prefix.trim_start_matches('/')).await? {
+        by_path
+            .entry(revision.path.clone())
+            .or_default()
+            .push(revision);
+    }
+
+    let mut report = PruneReport {
+        pruned: vec![],
+        bytes_reclaimed: 0,
+    };
+    let mut storage = if dry_run {
+        None
+    } else {
+        Some(Storage::open().await?)
+    };
+    // References a dry run would have released from each chunk so far
+    let mut released = HashMap::new();
+
+    for (_, mut revisions) in by_path {
+        // Oldest first from the database
+        revisions.reverse();
+
+        for revision in retention.to_prune(&revisions, now) {
+            report.bytes_reclaimed += match storage.as_mut() {
+                Some(storage) => match delete_revision(storage, revision, &data_dir).await {
+                    Ok(reclaimed) => reclaimed,
+                    Err(e) => {
+                        warn!("Couldn't delete {}: {}", revision.blake3_hash, e);
+                        continue;
+                    }
+                },
+                None => reclaimable(revision, &mut released).await?,
+            };
+            report.pruned.push(revision.clone());
+        }
+    }
+
+    flush_kv()?;
+
+    Ok(report)
+}
+
+/// Bytes deleting a revision would reclaim, given the chunk references deleting earlier ones would have released.
+/// Like when it's deleted, only chunks no other file refers to anymore count.
+async fn reclaimable(file: &FileInfo, released: &mut HashMap<blake3::Hash, u64>) -> Result<u64> {
+    if !file.chunked {
+        return Ok(file.bytes_written);
+    }
+
+    let mut reclaimed = 0;
+
+    for (chunk, file_refs, refs) in get_chunk_refs(&file.blake3_hash).await? {
+        let chunk_released = released.entry(chunk.blake3_hash).or_default();
+        *chunk_released += file_refs;
+
+        if *chunk_released >= refs {
+            reclaimed += chunk.bytes_written;
+        }
+    }
+
+    Ok(reclaimed)
+}
+
+/// Extracts a stored file to its path in the Forage Data folder
+async fn restore(storage: &mut Storage, file: &FileInfo, data_dir: &Path) -> Result<()> {
+    if file.chunked {
+        let manifest = get_manifest(&file.blake3_hash).await?;
+        return reassemble(storage, &manifest, &data_dir.join(&file.path)).await;
+    }
+
+    let blake3_hash = file.blake3_hash.to_hex();
+    let encoded = storage
+        .for_file(&blake3_hash, file.volume.as_deref())
+        .await
+        .fetch(&blake3_hash)
+        .await?;
+
+    extract(
+        &data_dir.join(&file.path),
+        &encoded.path,
+        &file.bao_hash,
+        &blake3_hash,
+        file.bytes_read,
+        file.compressed,
+        file.encrypted,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Extracts a chunked file from its manifest, one chunk at a time, fetching each from wherever it's stored
+async fn reassemble(storage: &mut Storage, manifest: &[ChunkInfo], out: &Path) -> Result<()> {
+    let mut offset = 0;
+
+    for chunk in manifest {
+        let chunk_hash = chunk.blake3_hash.to_hex();
+        let encoded = storage
+            .for_file(&chunk_hash, chunk.volume.as_deref())
+            .await
+            .fetch(&chunk_hash)
+            .await?;
+
+        offset += extract_chunk(out, offset, &encoded.path, chunk).await? as u64;
+    }
+
+    Ok(())
+}
+
+/// A row in a file listing. Directories collapse the files beneath them.
+pub struct Listing {
+    pub path: PathBuf,
+    pub bytes_read: u64, // Summed for directories
+    pub files: u64,
+    pub mime_type: Option<String>, // Not set for directories
+}
+
+impl Listing {
+    pub fn is_dir(&self) -> bool {
+        self.mime_type.is_none()
+    }
+}
+
+/// Lists stored files whose paths start with a prefix.
+/// Depth is counted from the directory the prefix is in, so `docs` lists the `docs` directory, while `docs/` lists what's in it.
+/// Files deeper than `depth` are collapsed into their directory, unless `depth` is 0.
+pub fn list_entries<'a>(
+    files: impl IntoIterator<Item = &'a FileInfo>,
+    prefix: &str,
+    depth: usize,
+) -> Vec<Listing> {
+    let prefix = prefix.trim_start_matches('/');
+    let base = Path::new(&prefix[..prefix.rfind('/').map_or(0, |i| i + 1)]);
+    let mut listings: BTreeMap<PathBuf, Listing> = BTreeMap::new();
+
+    for file in files {
+        if !file.path.to_string_lossy().starts_with(prefix) {
+            continue;
+        }
+
+        let relative = match file.path.strip_prefix(base) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+
+        if depth == 0 || relative.components().count() <= depth {
+            listings.insert(
+                file.path.clone(),
+                Listing {
+                    path: file.path.clone(),
+                    bytes_read: file.bytes_read,
+                    files: 1,
+                    mime_type: Some(file.mime_type.clone()),
+                },
+            );
+        } else {
+            let dir = base.join(relative.components().take(depth).collect::<PathBuf>());
+            let listing = listings.entry(dir.clone()).or_insert(Listing {
+                path: dir,
+                bytes_read: 0,
+                files: 0,
+                mime_type: None,
+            });
+            listing.bytes_read += file.bytes_read;
+            listing.files += 1;
+        }
+    }
+
+    listings.into_values().collect()
+}
+
+/// Lists stored files under a prefix, down to a directory depth
+pub async fn list_files(prefix: &str, depth: usize) -> Result<Vec<Listing>> {
+    Ok(list_entries(&get_files(None, None).await?, prefix, depth))
+}
+
+/// Trains a compression dictionary from a random sample of compressible files under a path
+pub async fn train_dictionary(prefix: &str, data_dir: &Path) -> Result<(u32, usize)> {
+    let compression = get_cfg().await?.compression;
+    let dir = data_dir.join(prefix);
+
+    let (dictionary_id, samples) =
+        blocking(move || train_dictionary_on(&dir, &compression)).await?;
+    flush_kv()?;
+
+    Ok((dictionary_id, samples))
+}
+
+fn train_dictionary_on(dir: &Path, compression: &CompressionCfg) -> Result<(u32, usize)> {
+    let mut paths = vec![];
+
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() && compression.compresses(&infer_mime_type(entry.path())?) {
+            paths.push(entry.into_path());
+        }
+    }
+
+    // TODO: replace all RNGs with CSPRNGs
+    let mut rng = rand::thread_rng();
+    let sampled = paths.choose_multiple(&mut rng, DICTIONARY_SAMPLE_FILES);
+    let mut samples = vec![];
+
+    for path in sampled {
+        let mut sample = vec![];
+        File::open(path)?
+            .take(DICTIONARY_SAMPLE_LEN)
+            .read_to_end(&mut sample)?;
+        samples.push(sample);
+    }
+
+    let (dictionary_id, dictionary) = train_dictionary_from(&samples, DICTIONARY_MAX_LEN)?;
+    insert_dictionary(dictionary_id, &dictionary)?;
+
+    Ok((dictionary_id, samples.len()))
+}
+
+/// Outcome of checking one slice of a stored file against its bao hash
+pub struct SliceCheck {
+    pub slice_index: u64,
+    pub error: Option<String>, // Why the slice couldn't be retrieved or didn't match, if it failed
+    pub elapsed: Duration,
+}
+
+impl SliceCheck {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+pub struct FileCheck {
+    pub blake3_hash: String,
+    pub path: PathBuf,
+    pub peer: Option<OnionAddressV3>, // Storage provider challenged, if the file isn't on this node's volumes
+    pub slices: Vec<SliceCheck>,
+}
+
+impl FileCheck {
+    pub fn passed(&self) -> bool {
+        self.slices.iter().all(SliceCheck::passed)
+    }
+}
+
+pub struct VerificationReport {
+    pub files: Vec<FileCheck>,
+    pub skipped: usize, // Files not checked because the storage provider's challenge budget ran out
+    pub elapsed: Duration,
+}
+
+impl VerificationReport {
+    pub fn passed(&self) -> bool {
+        self.files.iter().all(FileCheck::passed)
+    }
+
+    pub fn slices_failed(&self) -> usize {
+        self.files
+            .iter()
+            .flat_map(|file| &file.slices)
+            .filter(|slice| !slice.passed())
+            .count()
+    }
+}
+
+/// Encoding a slice of a stored file is extracted from, and the slice's index in it
+struct SliceTarget {
+    blake3_hash: blake3::Hash,
+    bao_hash: bao::Hash,
+    volume: Option<PathBuf>,
+    slice_index: u64,
+}
+
+/// Slices of a chunked file are those of its chunks, in order, so a slice is found in the chunk it falls in
+fn slice_target(file: &FileInfo, manifest: &[ChunkInfo], slice_index: u64) -> Result<SliceTarget> {
+    if !file.chunked {
+        return Ok(SliceTarget {
+            blake3_hash: file.blake3_hash,
+            bao_hash: file.bao_hash,
+            volume: file.volume.clone(),
+            slice_index,
+        });
+    }
+
+    let mut chunk_start = 0;
+
+    for chunk in manifest {
+        if slice_index < chunk_start + chunk.slices {
+            return Ok(SliceTarget {
+                blake3_hash: chunk.blake3_hash,
+                bao_hash: chunk.bao_hash,
+                volume: chunk.volume.clone(),
+                slice_index: slice_index - chunk_start,
+            });
+        }
+        chunk_start += chunk.slices;
+    }
+
+    Err(anyhow!(
+        "Slice {} is past the end of {}'s {} chunks",
+        slice_index,
+        file.blake3_hash,
+        manifest.len()
+    ))
+}
+
+/// Verify oldest file, newest file, and three files inbetween drawn at random slices, so larger files are checked more often.
+/// The number of files, and slices checked in each, are set in the [verification] section of cfg.toml.
+/// At most `challenge_budget` slices are asked of the storage provider, if one is given.
+pub async fn verify_data(challenge_budget: Option<u64>) -> Result<VerificationReport> {
+    let start = Instant::now();
+    let cfg = get_cfg().await?.verification;
+    let mut storage = Storage::open().await?;
+    let mut remaining = challenge_budget.unwrap_or(u64::MAX);
+    let mut files = vec![];
+    let mut skipped = 0;
+
+    for SampledFile {
+        file,
+        mut slice_indices,
+    } in get_verification_sample(cfg.files).await?
+    {
+        let blake3_hash = file.blake3_hash.to_hex().to_string();
+        let slice_count = (file.max_slice - file.min_slice).max(1);
+        let manifest = if file.chunked {
+            get_manifest(&file.blake3_hash).await?
+        } else {
+            vec![]
+        };
+
+        // Chunks of a file are all stored with the same backend it was uploaded to
+        let peer = match manifest.first() {
+            Some(chunk) => {
+                let chunk_hash = chunk.blake3_hash.to_hex();
+                storage
+                    .for_file(&chunk_hash, chunk.volume.as_deref())
+                    .await
+                    .peer()
+            }
+            None => storage
+                .for_file(&blake3_hash, file.volume.as_deref())
+                .await
+                .peer(),
+        };
+
+        let mut challenges = cfg.slices_per_file.min(slice_count as usize);
+        if peer.is_some() {
+            challenges = challenges.min(remaining.try_into().unwrap_or(usize::MAX));
+            remaining -= challenges as u64;
+
+            if challenges == 0 {
+                skipped += 1;
+                continue;
+            }
+        }
+
+        // Slices the file was drawn at are checked, along with others picked from the rest of it
+        while slice_indices.len() < challenges {
+            slice_indices.insert(rand::thread_rng().gen_range(0..slice_count));
+        }
+
+        let mut slices = vec![];
+
+        for slice_index in slice_indices.into_iter().take(challenges) {
+            let slice_start = Instant::now();
+
+            let checked = match slice_target(&file, &manifest, slice_index) {
+                Ok(SliceTarget {
+                    blake3_hash: target_hash,
+                    bao_hash,
+                    volume,
+                    slice_index: target_index,
+                }) => {
+                    let target_hash = target_hash.to_hex();
+                    match storage
+                        .for_file(&target_hash, volume.as_deref())
+                        .await
+                        .extract_slice(&target_hash, target_index)
+                        .await
+                    {
+                        Ok(slice) => {
+                            blocking(move || verify_slice(&bao_hash, &slice, target_index)).await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            let slice = SliceCheck {
+                slice_index,
+                error: checked.err().map(|e| e.to_string()),
+                elapsed: slice_start.elapsed(),
+            };
+
+            insert_verification(&VerificationRecord {
+                date_verified: Utc::now(),
+                peer: peer.map(|peer| peer.to_string()),
+                blake3_hash: blake3_hash.clone(),
+                slice_index,
+                passed: slice.passed(),
+                latency_ms: slice.elapsed.as_millis() as u64,
+                error: slice.error.clone(),
+            })
+            .await?;
+            slices.push(slice);
+        }
+
+        let checked = FileCheck {
+            blake3_hash,
+            path: file.path,
+            peer,
+            slices,
+        };
+        mark_as_verified(&checked.blake3_hash, checked.passed()).await?;
+        files.push(checked);
+    }
+
+    Ok(VerificationReport {
+        files,
+        skipped,
+        elapsed: start.elapsed(),
+    })
 }
 
 /// Fully delete a file from both storage client and storage provider, instead of just dropping it from the storage client
 pub async fn delete_file(hash: blake3::Hash) -> Result<()> {
-    remove_hash(hash)?;
-    todo!();
+    let file = get_file(&hash)
+        .await?
+        .ok_or_else(|| anyhow!("No revision {} was uploaded", hash))?;
+
+    if !file.removed {
+        let data_dir = get_cfg().await?.forage_data_dir;
+        let mut storage = Storage::open().await?;
+        delete_revision(&mut storage, &file, &data_dir).await?;
+        flush_kv()?;
+    }
+
+    Ok(())
+}
+
+/// Deletes every stored revision of a file. The file itself is left in the Forage Data folder, so the next upload stores it again.
+/// Returns the revisions that were deleted, along with the encoded bytes freed.
+pub async fn remove_file(path: &Path, data_dir: &Path) -> Result<(Vec<FileInfo>, u64)> {
+    let revisions: Vec<FileInfo> = history(path)
+        .await?
+        .into_iter()
+        .filter(|revision| !revision.removed && revision.path == path)
+        .collect();
+
+    if revisions.is_empty() {
+        return Err(anyhow!(
+            "{} has no stored revisions",
+            path.to_string_lossy()
+        ));
+    }
+
+    let mut storage = Storage::open().await?;
+
+    let mut bytes_reclaimed = 0;
+    for revision in &revisions {
+        bytes_reclaimed += delete_revision(&mut storage, revision, data_dir).await?;
+    }
+
+    flush_kv()?;
+
+    Ok((revisions, bytes_reclaimed))
+}
+
+/// Deletes a revision's encoded file from wherever it's stored, then forgets it was uploaded.
+/// Chunks of a chunked revision are only deleted once no other revision refers to them.
+/// Its row is kept, marked as removed, so its slices are never picked for verification.
+/// Returns the encoded bytes freed.
+async fn delete_revision(storage: &mut Storage, file: &FileInfo, data_dir: &Path) -> Result<u64> {
+    let blake3_hash = file.blake3_hash.to_hex();
+
+    let reclaimed = if file.chunked {
+        let mut reclaimed = 0;
+
+        for chunk in release_manifest(&file.blake3_hash).await? {
+            let chunk_hash = chunk.blake3_hash.to_hex();
+            remove_chunk(&chunk.blake3_hash)?;
+
+            // No file refers to the chunk anymore, so one that can't be deleted is only left behind
+            match storage
+                .for_file(&chunk_hash, chunk.volume.as_deref())
+                .await
+                .delete(&chunk_hash)
+                .await
+            {
+                Ok(()) => reclaimed += chunk.bytes_written,
+                Err(e) => warn!("Couldn't delete chunk {}: {}", chunk_hash, e),
+            }
+        }
+
+        reclaimed
+    } else {
+        storage
+            .for_file(&blake3_hash, file.volume.as_deref())
+            .await
+            .delete(&blake3_hash)
+            .await?;
+        file.bytes_written
+    };
+
+    mark_as_removed(file.blake3_hash).await?;
+    remove_hash(file.blake3_hash)?;
+    remove_path(
+        &data_dir.join(&file.path).to_string_lossy(),
+        &file.blake3_hash,
+    )?;
+
+    Ok(reclaimed)
 }
@@ -1,48 +1,482 @@
 use std::{
+    collections::HashMap,
     convert::TryInto,
     fs::{File, OpenOptions},
-    io::{ErrorKind, Read, Write},
-    path::Path,
+    io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bao::{
     decode::{Decoder, SliceDecoder},
     encode::{encoded_size, Encoder, SliceExtractor},
 };
 use blake3::Hasher;
+use chacha20poly1305::Key;
 use human_bytes::human_bytes;
 use log::{debug, error};
-use tokio::fs::create_dir_all;
+use tokio::{fs::create_dir_all, task::spawn_blocking};
 
-use crate::config::get_storage_path;
+use crate::{
+    backend::Staged,
+    chunk::Chunker,
+    config::{get_cfg, ChunkingCfg, CompressionCfg},
+    crypt::{file_key, DecryptingReader, EncryptingWriter},
+    db::{get_active_dictionary, get_chunk_slices, get_dictionary, ChunkInfo},
+};
+
+/// Bytes of a file covered by each slice that can be challenged
+pub const SLICE_LEN: u64 = 1024;
+const ZSTD_DICTIONARY_MAGIC: u32 = 0xEC30A437;
+/// Bytes at the start of a file its mime type is inferred from
+const MIME_SNIFF_LEN: u64 = 8192;
+/// Encrypted files up to this size are kept in memory from when they're hashed for their key until they're encoded, so they're only read once
+const ENCRYPT_BUFFER_LEN: u64 = 64 * 1024 * 1024;
+/// Chunks are hashed with a key derived from the hash key, so a chunk is never stored under the same name as a whole file
+const CHUNK_KEY_CONTEXT: &str = "Forage Storage Chunk Hash Key";
+
+/// Runs blocking file IO, hashing and encoding on tokio's blocking thread pool, so they don't stall the async runtime.
+/// This keeps a storage provider responsive while it serves many clients.
+pub async fn blocking<T, F>(task: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    spawn_blocking(task).await?
+}
+
+/// Length of a file once zero-padded out to the end of its last slice. Even an empty file takes up a slice.
+fn padded_len(len: u64) -> u64 {
+    len.div_ceil(SLICE_LEN).max(1) * SLICE_LEN
+}
+
+/// Bytes a file of `len` bytes takes up once it's encoded, if it isn't compressed or encrypted first
+pub fn encoded_len(len: u64) -> u64 {
+    encoded_size(padded_len(len)) as u64
+}
+
+/// Most bytes zstd compresses `len` bytes into, for contents that don't compress at all (`ZSTD_COMPRESSBOUND`)
+pub fn compress_bound(len: u64) -> u64 {
+    const SMALL_LEN: u64 = 128 * 1024;
+    let margin = if len < SMALL_LEN {
+        (SMALL_LEN - len) >> 11
+    } else {
+        0
+    };
+
+    len + (len >> 8) + margin
+}
+
+/// Describes file contents that were compressed with zstd before they were encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compressed {
+    pub len: u64,                   // compressed bytes, before padding
+    pub dictionary_id: Option<u32>, // trained dictionary used, if any
+}
 
 pub struct EncodedFileInfo {
     pub bao_hash: bao::Hash,
     pub read: u64,
     pub written: u64,
+    pub stored: u64, // bytes encoded, before padding
+    pub compressed: Option<Compressed>,
+    pub encrypted: bool,
+}
+
+/// Counts bytes as they're written through to another writer
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes bytes with a keyed hash as they're read from another reader
+struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Copies file contents to a writer, compressing them with zstd if a compression level is given
+fn write_contents(
+    file: &mut impl Read,
+    writer: &mut impl Write,
+    compression_level: Option<i32>,
+) -> Result<(usize, Option<Compressed>)> {
+    let level = match compression_level {
+        Some(level) => level,
+        None => return Ok((copy_reader_to_writer(file, writer, None)?, None)),
+    };
+
+    let mut counter = CountingWriter {
+        inner: writer,
+        count: 0,
+    };
+
+    let (read, dictionary_id) = match get_active_dictionary()? {
+        Some((dictionary_id, dictionary)) => {
+            let mut zstd_encoder =
+                zstd::Encoder::with_dictionary(&mut counter, level, &dictionary)?;
+            let read = copy_reader_to_writer(file, &mut zstd_encoder, None)?;
+            zstd_encoder.finish()?;
+            (read, Some(dictionary_id))
+        }
+        None => {
+            let mut zstd_encoder = zstd::Encoder::new(&mut counter, level)?;
+            let read = copy_reader_to_writer(file, &mut zstd_encoder, None)?;
+            zstd_encoder.finish()?;
+            (read, None)
+        }
+    };
+
+    let compressed = Compressed {
+        len: counter.count,
+        dictionary_id,
+    };
+
+    Ok((read, Some(compressed)))
 }
 
-/// Encode a file by its path using bao encoding.
-/// Returns bao hash, bytes read, bytes written, and the offset from which the bytes were written.
-pub async fn encode(path: &Path, hash_hex: &str) -> Result<EncodedFileInfo> {
+/// Encode a file by its path to `encoded_path` using bao encoding, compressing and encrypting it first if configured to.
+/// Returns bao hash, bytes read, bytes written, and how it was compressed and encrypted.
+pub async fn encode(path: &Path, hash_hex: &str, encoded_path: &Path) -> Result<EncodedFileInfo> {
+    let cfg = get_cfg().await?;
+    let key = if cfg.encrypt {
+        Some(file_key(&parse_blake3_hash(hash_hex)?))
+    } else {
+        None
+    };
+    let (path, encoded_path) = (path.to_owned(), encoded_path.to_owned());
+
+    blocking(move || encode_file(&path, &encoded_path, &cfg.compression, key)).await
+}
+
+/// Encodes a file to `encoded_path` with the given compression settings, encrypting it first if a key is given
+pub fn encode_file(
+    path: &Path,
+    encoded_path: &Path,
+    compression: &CompressionCfg,
+    key: Option<Key>,
+) -> Result<EncodedFileInfo> {
+    let compression_level = if compression.compresses(&infer_mime_type(path)?) {
+        Some(compression.level)
+    } else {
+        None
+    };
+
+    encode_contents(&mut File::open(path)?, encoded_path, compression_level, key)
+}
+
+/// A file hashed, sniffed and encoded in the same read
+pub struct HashedFileInfo {
+    pub blake3_hash: blake3::Hash,
+    pub mime_type: String,
+    pub encoded: EncodedFileInfo,
+}
+
+/// How a file is encrypted as it's encoded
+#[derive(Clone, Copy)]
+pub enum FileEncryption {
+    /// Stored unencrypted
+    Off,
+    /// Encrypted with the key derived from the file's hash, which is only known once the whole file is read
+    ByHash,
+    /// Encrypted with the key derived from a hash already known for the file, such as a cached one
+    ByKnownHash(blake3::Hash),
+}
+
+/// Hashes a file with a keyed hash, infers its mime type, and encodes it to `encoded_path`, reading the file only once.
+/// Contents are compressed first if configured to, but not encrypted.
+pub async fn hash_and_encode(
+    path: &Path,
+    hash_key: &[u8; 32],
+    encoded_path: &Path,
+) -> Result<HashedFileInfo> {
+    let compression = get_cfg().await?.compression;
+    let (path, hash_key, encoded_path) = (path.to_owned(), *hash_key, encoded_path.to_owned());
+
+    blocking(move || {
+        hash_and_encode_file(
+            &path,
+            &hash_key,
+            &encoded_path,
+            &compression,
+            FileEncryption::Off,
+        )
+    })
+    .await
+}
+
+/// Hashes, sniffs and encodes a file with the given compression and encryption settings.
+/// A file encrypted by its hash is kept in memory until it's hashed, unless it's larger than `ENCRYPT_BUFFER_LEN`, in which case it's read again.
+pub fn hash_and_encode_file(
+    path: &Path,
+    hash_key: &[u8; 32],
+    encoded_path: &Path,
+    compression: &CompressionCfg,
+    encryption: FileEncryption,
+) -> Result<HashedFileInfo> {
+    let (mut contents, mime_type) = sniff_file(path)?;
+
+    let compression_level = if compression.compresses(&mime_type) {
+        Some(compression.level)
+    } else {
+        None
+    };
+
+    let (blake3_hash, encoded) = match encryption {
+        FileEncryption::Off => {
+            let mut reader = HashingReader {
+                inner: contents,
+                hasher: Hasher::new_keyed(hash_key),
+            };
+            let encoded = encode_contents(&mut reader, encoded_path, compression_level, None)?;
+            (reader.hasher.finalize(), encoded)
+        }
+        FileEncryption::ByKnownHash(blake3_hash) => {
+            let key = Some(file_key(&blake3_hash));
+            let encoded = encode_contents(&mut contents, encoded_path, compression_level, key)?;
+            (blake3_hash, encoded)
+        }
+        FileEncryption::ByHash => {
+            let mut reader = HashingReader {
+                inner: contents,
+                hasher: Hasher::new_keyed(hash_key),
+            };
+            let mut buffered = vec![];
+            Read::by_ref(&mut reader)
+                .take(ENCRYPT_BUFFER_LEN)
+                .read_to_end(&mut buffered)?;
+            let remaining = copy_reader_to_writer(&mut reader, &mut io::sink(), None)?;
+
+            let blake3_hash = reader.hasher.finalize();
+            let key = Some(file_key(&blake3_hash));
+            let encoded = if remaining == 0 {
+                encode_contents(
+                    &mut Cursor::new(buffered),
+                    encoded_path,
+                    compression_level,
+                    key,
+                )?
+            } else {
+                drop(buffered);
+                encode_contents(&mut File::open(path)?, encoded_path, compression_level, key)?
+            };
+            (blake3_hash, encoded)
+        }
+    };
+
+    Ok(HashedFileInfo {
+        blake3_hash,
+        mime_type,
+        encoded,
+    })
+}
+
+/// Infers a file's mime type from its start, which is kept to be read again along with the rest of it
+fn sniff_file(path: &Path) -> Result<(impl Read, String)> {
     let mut file = File::open(path)?;
 
-    // Eventually this will need to be moved into a different function and replaced with a network call
+    let mut head = vec![];
+    Read::by_ref(&mut file)
+        .take(MIME_SNIFF_LEN)
+        .read_to_end(&mut head)?;
+    let mime_type = infer::get(&head)
+        .map_or("application/octet-stream", |t| t.mime_type())
+        .to_owned();
+
+    Ok((Cursor::new(head).chain(file), mime_type))
+}
+
+/// A chunk of a file, hashed and encoded by itself
+pub struct HashedChunk {
+    pub blake3_hash: blake3::Hash,
+    pub slices: u64,
+    /// Where the chunk was encoded to, unless it was already stored or appeared earlier in the file
+    pub encoded: Option<(Staged, EncodedFileInfo)>,
+}
+
+/// A file hashed and split into chunks in the same read
+pub struct ChunkedFileInfo {
+    pub blake3_hash: blake3::Hash,
+    pub mime_type: String,
+    pub bytes_read: u64,
+    pub chunks: Vec<HashedChunk>,
+}
+
+impl ChunkedFileInfo {
+    /// Encoded chunks waiting to be stored
+    pub fn staged(&self) -> impl Iterator<Item = &Path> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| chunk.encoded.as_ref().map(|(staged, _)| staged.path()))
+    }
+}
+
+/// Where a chunk is encoded to, next to the path its file was staged at
+fn chunk_staging_path(staged: &Path, chunk_index: usize) -> PathBuf {
+    let mut name = staged.file_name().unwrap_or_default().to_owned();
+    name.push(format!("-{:06}", chunk_index));
+    staged.with_file_name(name)
+}
+
+/// Hashes a file with a keyed hash, infers its mime type, and splits it into content-defined chunks, reading the file only once.
+/// Chunks that aren't stored yet are encoded next to `staged`, each compressed and encrypted by itself if configured to.
+pub async fn hash_and_encode_chunks(
+    path: &Path,
+    hash_key: &[u8; 32],
+    staged: &Path,
+) -> Result<ChunkedFileInfo> {
+    let cfg = get_cfg().await?;
+    let (path, hash_key, staged) = (path.to_owned(), *hash_key, staged.to_owned());
+
+    blocking(move || {
+        hash_and_encode_chunks_of(
+            &path,
+            &hash_key,
+            &staged,
+            &cfg.chunking,
+            &cfg.compression,
+            cfg.encrypt,
+        )
+    })
+    .await
+}
+
+/// Hashes and chunks a file with the given settings. Chunks encoded before one fails are removed along with it.
+pub fn hash_and_encode_chunks_of(
+    path: &Path,
+    hash_key: &[u8; 32],
+    staged: &Path,
+    chunking: &ChunkingCfg,
+    compression: &CompressionCfg,
+    encrypt: bool,
+) -> Result<ChunkedFileInfo> {
+    let (contents, mime_type) = sniff_file(path)?;
+
+    let compression_level = if compression.compresses(&mime_type) {
+        Some(compression.level)
+    } else {
+        None
+    };
+
+    let chunk_key = blake3::derive_key(CHUNK_KEY_CONTEXT, hash_key);
+    let mut hasher = Hasher::new_keyed(hash_key);
+    let mut bytes_read = 0;
+    let mut chunks: Vec<HashedChunk> = vec![];
+    let mut seen: HashMap<blake3::Hash, u64> = HashMap::new();
+
+    let chunk_contents = || -> Result<()> {
+        for (chunk_index, contents) in Chunker::new(contents, chunking).enumerate() {
+            let contents = contents?;
+            hasher.update(&contents);
+            bytes_read += contents.len() as u64;
+
+            let blake3_hash = blake3::keyed_hash(&chunk_key, &contents);
+            let known = match seen.get(&blake3_hash) {
+                Some(slices) => Some(*slices),
+                None => get_chunk_slices(&blake3_hash)?,
+            };
+
+            if let Some(slices) = known {
+                chunks.push(HashedChunk {
+                    blake3_hash,
+                    slices,
+                    encoded: None,
+                });
+                continue;
+            }
+
+            // Chunks are hashed before they're encoded, so they can be encrypted with keys derived from their hashes
+            let key = if encrypt {
+                Some(file_key(&blake3_hash))
+            } else {
+                None
+            };
+            let encoded_path = Staged::new(chunk_staging_path(staged, chunk_index));
+            let encoded = encode_contents(
+                &mut contents.as_slice(),
+                encoded_path.path(),
+                compression_level,
+                key,
+            )?;
+
+            let slices = encoded.stored.div_ceil(SLICE_LEN).max(1);
+            seen.insert(blake3_hash, slices);
+            chunks.push(HashedChunk {
+                blake3_hash,
+                slices,
+                encoded: Some((encoded_path, encoded)),
+            });
+        }
+
+        Ok(())
+    };
+
+    chunk_contents()?;
+
+    Ok(ChunkedFileInfo {
+        blake3_hash: hasher.finalize(),
+        mime_type,
+        bytes_read,
+        chunks,
+    })
+}
+
+/// Encodes contents to `encoded_path`, compressing them with zstd if a level is given, then encrypting them if a key is given
+fn encode_contents(
+    contents: &mut impl Read,
+    encoded_path: &Path,
+    compression_level: Option<i32>,
+    key: Option<Key>,
+) -> Result<EncodedFileInfo> {
     let encoded_file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .truncate(true)
-        .open(get_storage_path().await?.join(hash_hex))?;
+        .open(encoded_path)?;
 
     let mut encoder = Encoder::new(&encoded_file);
-    let read = copy_reader_to_writer(&mut file, &mut encoder, 0)?;
+    let mut stored = CountingWriter {
+        inner: &mut encoder,
+        count: 0,
+    };
+
+    // Contents are compressed, then encrypted, then encoded
+    let (read, compressed) = match &key {
+        Some(key) => {
+            let mut encryptor = EncryptingWriter::new(&mut stored, key)?;
+            let written = write_contents(contents, &mut encryptor, compression_level)?;
+            encryptor.finish()?;
+            written
+        }
+        None => write_contents(contents, &mut stored, compression_level)?,
+    };
 
     // Generate filler bytes for remainder of 1024 byte slice
-    let len = 1024 - read % 1024;
-    let buf = vec![0u8; len];
-    let written = encoded_size((read + len) as u64) as u64;
+    let stored = stored.count;
+    let padded = padded_len(stored);
+    let buf = vec![0u8; (padded - stored) as usize];
+    let written = encoded_size(padded) as u64;
 
     encoder.write_all(&buf)?;
     encoder.flush()?;
@@ -53,26 +487,26 @@ pub async fn encode(path: &Path, hash_hex: &str) -> Result<EncodedFileInfo> {
         bao_hash,
         read: read as u64,
         written,
+        stored,
+        compressed,
+        encrypted: key.is_some(),
     })
 }
 
-const SLICE_LEN: u64 = 1024;
-
-pub async fn verify(
-    bao_hash: &bao::Hash,
-    encoded_file_path: &Path,
-    slice_index: u64,
-) -> Result<()> {
-    // Client
+/// Provider half of a slice challenge: extracts a slice, along with the parts of the bao tree needed to verify it, from an encoded file
+pub fn extract_slice(encoded_file_path: &Path, slice_index: u64) -> Result<Vec<u8>> {
     let encoded_file = File::open(encoded_file_path)?;
-
-    // Provider
     let mut extractor = SliceExtractor::new(encoded_file, slice_index * SLICE_LEN, SLICE_LEN);
     let mut slice = vec![];
     extractor.read_to_end(&mut slice)?;
 
-    // Client
-    let mut decoder = SliceDecoder::new(&*slice, bao_hash, slice_index * SLICE_LEN, SLICE_LEN);
+    Ok(slice)
+}
+
+/// Client half of a slice challenge: checks an extracted slice against the bao hash of the file it was extracted from.
+/// Only the bao hash is needed, so the file itself doesn't need to be kept locally.
+pub fn verify_slice(bao_hash: &bao::Hash, slice: &[u8], slice_index: u64) -> Result<()> {
+    let mut decoder = SliceDecoder::new(slice, bao_hash, slice_index * SLICE_LEN, SLICE_LEN);
 
     let mut decoded = vec![];
     match decoder.read_to_end(&mut decoded) {
@@ -90,14 +524,40 @@ pub async fn verify(
     }
 }
 
+/// Decode an encoded file to `out`, decrypting and decompressing it if that was done before it was encoded
 pub async fn extract(
     out: &Path,
+    encoded_file_path: &Path,
     bao_hash: &bao::Hash,
     blake3_hash: &str,
     file_size: u64,
+    compressed: Option<Compressed>,
+    encrypted: bool,
 ) -> Result<usize> {
-    let encoded_file = File::open(get_storage_path().await?.join(blake3_hash))?;
+    create_parent_dir(out).await?;
+
+    let (out, encoded_file_path, bao_hash) =
+        (out.to_owned(), encoded_file_path.to_owned(), *bao_hash);
+    let key = if encrypted {
+        Some(file_key(&parse_blake3_hash(blake3_hash)?))
+    } else {
+        None
+    };
 
+    blocking(move || {
+        extract_file(
+            &out,
+            &encoded_file_path,
+            &bao_hash,
+            file_size,
+            compressed,
+            key,
+        )
+    })
+    .await
+}
+
+async fn create_parent_dir(out: &Path) -> Result<()> {
     if let Some(parent_dir) = out.to_path_buf().parent() {
         // Will probably error if a file exists where a directory should be... TODO: Handle this case gracefully
         if !Path::new(parent_dir).exists() {
@@ -105,6 +565,17 @@ pub async fn extract(
         }
     }
 
+    Ok(())
+}
+
+fn extract_file(
+    out: &Path,
+    encoded_file_path: &Path,
+    bao_hash: &bao::Hash,
+    file_size: u64,
+    compressed: Option<Compressed>,
+    key: Option<Key>,
+) -> Result<usize> {
     let mut extracted_file = OpenOptions::new()
         .read(true)
         .write(true)
@@ -112,9 +583,94 @@ pub async fn extract(
         .truncate(true) // Warning! Will overwrite data
         .open(out)?;
 
-    let extractor = SliceExtractor::new(encoded_file, 0, file_size);
-    let mut decoder = Decoder::new(extractor, bao_hash);
-    let bytes_read = copy_reader_to_writer(&mut decoder, &mut extracted_file, file_size as usize)?;
+    decode_contents(
+        &mut extracted_file,
+        encoded_file_path,
+        bao_hash,
+        file_size,
+        compressed,
+        key,
+    )
+}
+
+/// Decodes one chunk of a file into `out` at `offset`, decrypting and decompressing it if that was done before it was encoded.
+/// A chunked file is reassembled from its manifest by extracting each of its chunks in order, so the first one replaces whatever was at `out`.
+pub async fn extract_chunk(
+    out: &Path,
+    offset: u64,
+    encoded_chunk_path: &Path,
+    chunk: &ChunkInfo,
+) -> Result<usize> {
+    if offset == 0 {
+        create_parent_dir(out).await?;
+    }
+
+    let (out, encoded_chunk_path, chunk) =
+        (out.to_owned(), encoded_chunk_path.to_owned(), chunk.clone());
+    let key = if chunk.encrypted {
+        Some(file_key(&chunk.blake3_hash))
+    } else {
+        None
+    };
+
+    blocking(move || {
+        let mut extracted_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0) // Warning! Will overwrite data
+            .open(&out)?;
+        extracted_file.seek(SeekFrom::Start(offset))?;
+
+        decode_contents(
+            &mut extracted_file,
+            &encoded_chunk_path,
+            &chunk.bao_hash,
+            chunk.bytes_read,
+            chunk.compressed,
+            key,
+        )
+    })
+    .await
+}
+
+fn decode_contents(
+    writer: &mut impl Write,
+    encoded_file_path: &Path,
+    bao_hash: &bao::Hash,
+    file_size: u64,
+    compressed: Option<Compressed>,
+    key: Option<Key>,
+) -> Result<usize> {
+    let encoded_file = File::open(encoded_file_path)?;
+
+    // Each stage ends on its own (at the end of the zstd frame or the final encrypted chunk), or at the file size
+    let decoder = Decoder::new(encoded_file, bao_hash);
+
+    let decrypted: Box<dyn Read> = match key {
+        Some(key) => Box::new(DecryptingReader::new(decoder, &key)?),
+        None => Box::new(decoder),
+    };
+
+    let mut decompressed: Box<dyn Read> = match compressed {
+        Some(Compressed {
+            dictionary_id: Some(dictionary_id),
+            ..
+        }) => {
+            let dictionary = get_dictionary(dictionary_id)?
+                .ok_or_else(|| anyhow!("Compression dictionary {} not found", dictionary_id))?;
+            Box::new(
+                zstd::Decoder::with_dictionary(BufReader::new(decrypted), &dictionary)?
+                    .single_frame(),
+            )
+        }
+        Some(Compressed {
+            dictionary_id: None,
+            ..
+        }) => Box::new(zstd::Decoder::new(decrypted)?.single_frame()),
+        None => decrypted,
+    };
+
+    let bytes_read = copy_reader_to_writer(&mut decompressed, writer, Some(file_size as usize))?;
 
     debug!("bytes written: {}", human_bytes(bytes_read as f64));
 
@@ -138,12 +694,26 @@ pub fn parse_blake3_hash(hash_hex: &str) -> Result<blake3::Hash> {
 pub fn hash_file(path: &Path, hash_key: &[u8; 32]) -> Result<blake3::Hash> {
     let mut file_reader = File::open(path)?;
     let mut hasher = Hasher::new_keyed(hash_key);
-    let bytes_read = copy_reader_to_writer(&mut file_reader, &mut hasher, 0)?;
+    let bytes_read = copy_reader_to_writer(&mut file_reader, &mut hasher, None)?;
     let file_hash = hasher.finalize();
     debug!("path: {}, size: {}", path.to_string_lossy(), bytes_read);
     Ok(file_hash)
 }
 
+/// Trains a zstd dictionary from samples of file contents, returning its id along with it
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<(u32, Vec<u8>)> {
+    let dictionary = zstd::dict::from_samples(samples, max_size)?;
+
+    // Dictionary header is a 4 byte magic number followed by a 4 byte little-endian dictionary id
+    if dictionary.len() < 8 || dictionary[..4] != ZSTD_DICTIONARY_MAGIC.to_le_bytes() {
+        return Err(anyhow!("Trained dictionary is missing its header"));
+    }
+
+    let dictionary_id = u32::from_le_bytes(dictionary[4..8].try_into()?);
+
+    Ok((dictionary_id, dictionary))
+}
+
 pub fn infer_mime_type(path: &Path) -> Result<String> {
     let mime_type = infer::get_from_path(path)?
         .map_or("application/octet-stream", |t| t.mime_type())
@@ -154,11 +724,11 @@ pub fn infer_mime_type(path: &Path) -> Result<String> {
     Ok(mime_type)
 }
 
-// Limit of 0 means there's no limit
+// Copies until the reader runs out, or `limit` bytes have been copied if there is one
 fn copy_reader_to_writer(
     reader: &mut impl Read,
     writer: &mut impl Write,
-    limit: usize,
+    limit: Option<usize>,
 ) -> Result<usize> {
     // At least 16 KiB is necessary to use AVX-512 with BLAKE3.
     let mut buf = [0; 65536];
@@ -172,7 +742,7 @@ fn copy_reader_to_writer(
             Err(e) => return Err(e.into()),
         };
 
-        if limit != 0 && read + len > limit {
+        if let Some(limit) = limit.filter(|limit| read + len > *limit) {
             writer.write_all(&buf[..limit - read])?;
             return Ok(limit);
         } else {
@@ -1,44 +1,108 @@
 use anyhow::Result;
-use log::{error, info, warn};
-use tokio::signal;
+use std::path::Path;
 
+use chrono::{DateTime, Utc};
+use human_bytes::human_bytes;
+use log::{error, info};
+
+pub mod backend;
+pub mod chunk;
 pub mod config;
+pub mod crypt;
+pub mod daemon;
 pub mod db;
 pub mod file;
 pub mod hash;
 pub mod net;
+pub mod status;
 
-pub fn new_client(label: &str, cap: Option<u64>) {
+/// Issues credentials for a new storage client, returning the connection string to share with them
+pub async fn new_client(label: &str, cap: Option<u64>) -> Result<String> {
     info!(
         "Creating a new channel for {} with a cap of {:?}",
         label, cap
     );
-    warn!("Not yet implemented");
-    todo!();
+
+    let cfg = config::get_cfg().await?;
+    let connection =
+        net::auth::ConnectionString::generate(net::tor::onion_address()?, cfg.tor.service_port);
+
+    db::insert_peer(db::PeerInfo {
+        tor_v3: connection.client_address().to_string(),
+        label: Some(label.to_owned()),
+        date_created: Utc::now(),
+        client: true,
+        provider: false,
+        cap,
+        bytes_used: 0,
+    })
+    .await?;
+
+    info!(
+        "Share this connection string with {} (and only with them):\n{}",
+        label, connection
+    );
+
+    Ok(connection.to_string())
 }
 
-pub fn open_channel(address: &str) {
-    info!("Opening a channel to {}", address);
-    warn!("Not yet implemented");
-    todo!();
+/// Opens a storage channel to a provider using the connection string it issued.
+/// Once opened, files are uploaded to, downloaded from, and verified against the provider.
+pub async fn open_channel(connection: &str) -> Result<()> {
+    let connection: net::auth::ConnectionString = connection.parse()?;
+    info!("Opening a channel to {}", connection.provider);
+
+    // Connecting authenticates both sides, so the provider is known to be genuine before it's recorded
+    let channel = net::channel::connect(&connection).await?;
+
+    db::upsert_provider(&channel.provider.to_string()).await?;
+    db::insert_channel(&connection.to_string())?;
+
+    info!(
+        "Channel opened. Files will be stored with {}",
+        channel.provider
+    );
+
+    Ok(())
 }
 
-pub async fn upload(prefix: &str) -> Result<()> {
+/// Uploads files under a prefix. Unless `rescan` is set, files that look unchanged since they were last scanned aren't read again.
+pub async fn upload(prefix: &str, rescan: bool) -> Result<()> {
     info!("Storing data in Forage Data directory over available storage channels...");
     let data_dir = config::get_data_dir().await?;
-    file::upload_path(prefix, &data_dir).await?;
+    file::upload_path(prefix, &data_dir, rescan).await?;
 
     Ok(())
 }
 
-pub async fn download(prefix: &str) -> Result<()> {
+/// Retrieves files under a prefix. Older revisions can be retrieved by the time they were current, or by their hash.
+/// Local files are compared by hash, which is cached between scans unless `rescan` is set.
+pub async fn download(
+    prefix: &str,
+    at: Option<DateTime<Utc>>,
+    rev: Option<&str>,
+    rescan: bool,
+) -> Result<()> {
     info!("Retrieving unsynced files over available storage channels...");
 
     let data_dir = config::get_data_dir().await?;
 
+    if let Some(rev) = rev {
+        let path = file::download_revision(&hash::parse_blake3_hash(rev)?, &data_dir).await?;
+        info!(
+            "Revision {} restored to {}.",
+            rev,
+            data_dir.join(path).to_string_lossy()
+        );
+        return Ok(());
+    }
+
     // Check paths of existing files in the Forage Data dir
     // If a file is absent, extract it to its relative path
-    let updated = file::download_by_prefix(prefix, &data_dir).await?;
+    let updated = match at {
+        Some(at) => file::download_at(prefix, &data_dir, at, rescan).await?,
+        None => file::download_by_prefix(prefix, &data_dir, rescan).await?,
+    };
 
     info!(
         "{} files in {}/{} updated.",
@@ -58,64 +122,295 @@ pub async fn download(prefix: &str) -> Result<()> {
     Ok(())
 }
 
+/// Deletes a file and all its revisions from storage
+pub async fn rm(path: &str) -> Result<()> {
+    let data_dir = config::get_data_dir().await?;
+    let (removed, bytes_reclaimed) =
+        file::remove_file(Path::new(path.trim_start_matches('/')), &data_dir).await?;
+
+    info!(
+        "{} revisions of {} removed, reclaiming {}.",
+        removed.len(),
+        path,
+        human_bytes(bytes_reclaimed as f64)
+    );
+
+    Ok(())
+}
+
+/// Lists the revisions of a file, newest first
+pub async fn history(path: &str) -> Result<()> {
+    let revisions = file::history(Path::new(path.trim_start_matches('/'))).await?;
+
+    if revisions.is_empty() {
+        info!("No revisions of {} were uploaded.", path);
+        return Ok(());
+    }
+
+    let rows: Vec<String> = revisions
+        .iter()
+        .map(|revision| {
+            format!(
+                "{hash}\t{modified}\t{size}\t{state}",
+                hash = revision.blake3_hash.to_hex(),
+                modified = revision.date_modified.to_rfc3339(),
+                size = human_bytes(revision.bytes_read as f64),
+                state = if revision.removed {
+                    "removed"
+                } else if revision.dropped {
+                    "dropped"
+                } else {
+                    "current"
+                },
+            )
+        })
+        .collect();
+
+    info!(
+        "{} revisions of {}:\n{}",
+        revisions.len(),
+        path,
+        rows.join("\n")
+    );
+
+    Ok(())
+}
+
+/// Removes older revisions the retention rules in cfg.toml don't keep, or just reports them on a dry run
+pub async fn prune(prefix: &str, dry_run: bool) -> Result<()> {
+    let file::PruneReport {
+        pruned,
+        bytes_reclaimed,
+    } = file::prune(prefix, dry_run).await?;
+
+    let rows: Vec<String> = pruned
+        .iter()
+        .map(|revision| {
+            format!(
+                "{hash}\t{modified}\t{size}\t{path}",
+                hash = revision.blake3_hash.to_hex(),
+                modified = revision.date_modified.to_rfc3339(),
+                size = human_bytes(revision.bytes_written as f64),
+                path = revision.path.to_string_lossy(),
+            )
+        })
+        .collect();
+
+    info!(
+        "{} {} revisions, reclaiming {}:\n{}",
+        if dry_run { "Would prune" } else { "Pruned" },
+        pruned.len(),
+        human_bytes(bytes_reclaimed as f64),
+        rows.join("\n")
+    );
+
+    Ok(())
+}
+
+pub async fn train_dictionary(prefix: &str) -> Result<()> {
+    info!("Training a compression dictionary on files in the Forage Data directory...");
+
+    let data_dir = config::get_data_dir().await?;
+    let (dictionary_id, samples) = file::train_dictionary(prefix, &data_dir).await?;
+
+    info!(
+        "Dictionary {} trained on {} files. It will be used to compress newly uploaded files.",
+        dictionary_id, samples
+    );
+
+    Ok(())
+}
+
 pub async fn verify() -> Result<()> {
     info!("Verifying data possession on existing storage channels...");
 
-    let slice_count = db::get_max_slice().await?;
+    let report = file::verify_data(None).await?;
 
-    if slice_count == 0 {
+    if report.files.is_empty() {
         info!("No slices to verify. Try adding some files.");
-    } else {
-        let db::SliceIndexInfo {
-            blake3_hash,
-            bao_hash,
-            file_slice_index: slice_index,
-            data_dir_path,
-        } = db::get_random_slice_index(slice_count).await?;
-
-        let bao_hash = hash::parse_bao_hash(&bao_hash)?;
-        let encoded_path = config::get_storage_path().await?.join(blake3_hash);
+        return Ok(());
+    }
+
+    for checked in &report.files {
+        let indices: Vec<String> = checked
+            .slices
+            .iter()
+            .map(|slice| slice.slice_index.to_string())
+            .collect();
+        let elapsed: std::time::Duration = checked.slices.iter().map(|slice| slice.elapsed).sum();
+
+        if checked.passed() {
+            info!(
+                "File verified: {}\tSlices: {}\t{:.2?}",
+                checked.path.to_string_lossy(),
+                indices.join(", "),
+                elapsed
+            );
+        } else {
+            for slice in checked.slices.iter().filter(|slice| !slice.passed()) {
+                error!(
+                    "File unverified: {}\tSlice: {}\tError: {}",
+                    checked.path.to_string_lossy(),
+                    slice.slice_index,
+                    slice.error.as_deref().unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    if report.passed() {
         info!(
-            "File chosen: {}\tIndex: {} of {} slices",
-            data_dir_path, slice_index, slice_count
+            "Verification successful. {} files checked in {:.2?}.",
+            report.files.len(),
+            report.elapsed
+        );
+    } else {
+        error!(
+            "Verification unsuccessful. {} slices failed in {} files checked.",
+            report.slices_failed(),
+            report.files.len()
         );
+    }
 
-        match hash::verify(&bao_hash, &encoded_path, slice_index).await {
-            Ok(()) => {
-                info!("Verification successful.");
-            }
-            Err(e) => {
-                error!("Verification unsuccessful.\tError: {}", e);
-            }
+    alert_failures(&db::get_track_records().await?).await
+}
+
+/// Shows how each storage provider, and this node's volumes, have held up to verification, and the latest checks
+pub async fn verify_history(limit: usize) -> Result<()> {
+    let records = db::get_track_records().await?;
+
+    if records.is_empty() {
+        info!("Nothing has been verified yet.");
+        return Ok(());
+    }
+
+    let peer_name = |peer: &Option<String>| peer.clone().unwrap_or_else(|| "this node".to_owned());
+    let date =
+        |date: Option<DateTime<Utc>>| date.map_or_else(|| "never".to_owned(), |d| d.to_rfc3339());
+
+    let summary: Vec<String> = records
+        .iter()
+        .map(|record| {
+            format!(
+                "{peer}\t{checks} checks\t{failures} failed\t{latency} ms average\tlast passed {passed}\tlast failed {failed}",
+                peer = peer_name(&record.peer),
+                checks = record.checks,
+                failures = record.failures,
+                latency = record.average_latency_ms,
+                passed = date(record.last_passed),
+                failed = date(record.last_failed),
+            )
+        })
+        .collect();
+
+    let recent: Vec<String> = db::get_verifications(limit)
+        .await?
+        .iter()
+        .map(|verification| {
+            format!(
+                "{date}\t{peer}\t{hash}\t{slice}\t{outcome}\t{latency} ms\t{error}",
+                date = verification.date_verified.to_rfc3339(),
+                peer = peer_name(&verification.peer),
+                hash = verification.blake3_hash,
+                slice = verification.slice_index,
+                outcome = if verification.passed {
+                    "passed"
+                } else {
+                    "FAILED"
+                },
+                latency = verification.latency_ms,
+                error = verification.error.as_deref().unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    info!(
+        "Track record:\n{}\nLatest {} verifications:\n{}",
+        summary.join("\n"),
+        recent.len(),
+        recent.join("\n")
+    );
+
+    alert_failures(&records).await
+}
+
+/// Reports storage providers, or this node's volumes, that have failed verification too many times in a row
+pub(crate) async fn alert_failures(records: &[db::TrackRecord]) -> Result<()> {
+    let threshold = config::get_cfg().await?.verification.alert_after_failures;
+
+    for record in records {
+        if threshold > 0 && record.consecutive_failures >= threshold {
+            error!(
+                "{} failed its last {} verifications. Data stored there may be lost.",
+                record.peer.as_deref().map_or_else(
+                    || "This node".to_owned(),
+                    |peer| format!("Storage provider {}", peer)
+                ),
+                record.consecutive_failures
+            );
         }
     }
 
     Ok(())
 }
 
-pub async fn list_files(_prefix: &str, _depth: usize) -> Result<()> {
-    // TODO: support prefix and depth parameters
+pub async fn list_files(prefix: &str, depth: usize) -> Result<()> {
     let data_dir = config::get_data_dir().await?;
-    let files = db::list_files().await?;
+    let listings = file::list_files(prefix, depth).await?;
+    let files: u64 = listings.iter().map(|listing| listing.files).sum();
+
+    let rows: Vec<String> = listings
+        .iter()
+        .map(|listing| {
+            let path = listing.path.to_string_lossy();
+
+            if listing.is_dir() {
+                format!(
+                    "{size}\t\t{files} files\t{path}/",
+                    size = human_bytes(listing.bytes_read as f64),
+                    files = listing.files,
+                    path = path,
+                )
+            } else {
+                format!(
+                    "{size}\t\t{mime_type}\t{path}",
+                    size = human_bytes(listing.bytes_read as f64),
+                    mime_type = listing.mime_type.as_deref().unwrap_or_default(),
+                    path = path,
+                )
+            }
+        })
+        .collect();
+
     info!(
         "{} files stored in {}:\n{}",
-        files.len(),
+        files,
         data_dir.file_name().unwrap().to_string_lossy(),
-        files.join("\n")
+        rows.join("\n")
     );
 
     Ok(())
 }
 
+/// Onion v3 address this node's hidden service is published at
+pub fn onion_address() -> Result<String> {
+    Ok(net::tor::onion_address()?.to_string())
+}
+
 pub async fn start() -> Result<()> {
     info!("Starting Forage node...");
-    signal::ctrl_c().await?;
-
-    Ok(())
+    daemon::run(daemon::shutdown_signal()).await
 }
 
-pub fn status() {
-    info!("Status from Forage node... Press CTRL-C to stop");
-    warn!("Not yet implemented");
-    todo!();
+/// Prints a summary of this node's storage health, as JSON if requested
+pub async fn status(json: bool) -> Result<()> {
+    let status = status::get_status().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        info!("Status from Forage node:\n{}", status);
+    }
+
+    Ok(())
 }
@@ -1,6 +1,7 @@
 use std::{env, error::Error, path::PathBuf, process};
 
 use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use log::error;
 use structopt::StructOpt;
 
@@ -13,13 +14,13 @@ enum Commands {
     NewClient {
         /// Internal label to associate with client
         label: String,
-        /// Storage cap for client
+        /// Storage cap for client, in megabytes
         cap: Option<u64>,
     },
-    /// Open a storage channel to a permissioned storage provider. Will prompt for auth.
+    /// Open a storage channel to a permissioned storage provider
     OpenChannel {
-        /// Tor Onion v3 address to authorized storage node.
-        address: String,
+        /// Connection string issued by the storage provider with new-client
+        connection: String,
         // /// How many sats to dedicate to this channel?
         // balance: usize,
         // /// Rate to use for opening a channel on L1, in sats/vB.
@@ -47,15 +48,59 @@ enum Commands {
         /// Restrict pruning to just paths with this prefix (relative to the Forage Data folder)
         #[structopt(default_value = "")]
         prefix: String,
+        /// Hash every file again, even those that look unchanged since they were last scanned
+        #[structopt(long)]
+        rescan: bool,
     },
     /// Retrieve a file by its path prefix over available storage channels (leave empty to retrieve all files, de-duplicating as necessary)
     Download {
         /// Path prefix. Multiple path matches will be saved to separate files and folders.
         #[structopt(default_value = "")]
         prefix: String,
+        /// Retrieve the revisions that were current at this time (RFC 3339, or a UTC date and optional time)
+        #[structopt(long, parse(try_from_str = parse_timestamp))]
+        at: Option<DateTime<Utc>>,
+        /// Retrieve a specific revision by its hash, as shown by history
+        #[structopt(long, conflicts_with = "at")]
+        rev: Option<String>,
+        /// Hash every local file again, even those that look unchanged since they were last scanned
+        #[structopt(long)]
+        rescan: bool,
+    },
+    /// Remove older revisions of files that the retention rules in cfg.toml don't keep
+    Prune {
+        /// Restrict pruning to just paths with this prefix (relative to the Forage Data folder)
+        #[structopt(default_value = "")]
+        prefix: String,
+        /// Report what would be pruned without removing anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Delete a file and all its revisions from storage
+    Rm {
+        /// Path relative to the Forage Data folder
+        path: String,
+    },
+    /// List the revisions of a file, newest first
+    History {
+        /// Path relative to the Forage Data folder
+        path: String,
+    },
+    /// Train a compression dictionary on a sample of files in the Forage Data folder, used when compression is enabled
+    TrainDictionary {
+        /// Restrict sampling to just paths with this prefix (relative to the Forage Data folder)
+        #[structopt(default_value = "")]
+        prefix: String,
     },
     /// Issues a challenge to verify if a provider is still hosting data for this storage channel.
-    Verify,
+    Verify {
+        /// Show past verifications instead, with each storage provider's track record
+        #[structopt(long)]
+        history: bool,
+        /// Number of past verifications to show
+        #[structopt(long, default_value = "20")]
+        limit: usize,
+    },
     /// List files stored over storage channel
     ListFiles {
         /// Filter paths by prefix
@@ -81,24 +126,59 @@ enum Commands {
     /// Start storage node
     Start,
     /// Get node status
-    Status,
+    Status {
+        /// Print status as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(date_time.with_timezone(&Utc));
+    }
+
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S") {
+        return Ok(DateTime::from_utc(date_time, Utc));
+    }
+
+    // A date on its own means the end of that day
+    let date = NaiveDate::parse_from_str(timestamp, "%Y-%m-%d")?;
+    Ok(DateTime::from_utc(date.and_hms(23, 59, 59), Utc))
 }
 
 pub async fn try_main() -> Result<()> {
     #[allow(unused_variables)]
     match Commands::from_args() {
-        Commands::NewClient { label, cap } => forage::new_client(&label, cap),
-        Commands::OpenChannel { address } => forage::open_channel(&address),
+        Commands::NewClient { label, cap } => {
+            forage::new_client(&label, cap).await?;
+        }
+        Commands::OpenChannel { connection } => forage::open_channel(&connection).await?,
         Commands::ListChannels { providers, clients } => unimplemented!(),
         Commands::CloseChannel { address, force } => unimplemented!(),
-        Commands::Upload { prefix } => forage::upload(&prefix).await?,
-        Commands::Download { prefix } => forage::download(&prefix).await?,
-        Commands::Verify => forage::verify().await?,
+        Commands::Upload { prefix, rescan } => forage::upload(&prefix, rescan).await?,
+        Commands::Download {
+            prefix,
+            at,
+            rev,
+            rescan,
+        } => forage::download(&prefix, at, rev.as_deref(), rescan).await?,
+        Commands::Prune { prefix, dry_run } => forage::prune(&prefix, dry_run).await?,
+        Commands::Rm { path } => forage::rm(&path).await?,
+        Commands::History { path } => forage::history(&path).await?,
+        Commands::TrainDictionary { prefix } => forage::train_dictionary(&prefix).await?,
+        Commands::Verify { history, limit } => {
+            if history {
+                forage::verify_history(limit).await?
+            } else {
+                forage::verify().await?
+            }
+        }
         Commands::ListFiles { prefix, depth } => forage::list_files(&prefix, depth).await?,
         Commands::Allocate { path, size } => unimplemented!(),
         Commands::Transfer { address } => unimplemented!(),
         Commands::Start => forage::start().await?,
-        Commands::Status => forage::status(),
+        Commands::Status { json } => forage::status(json).await?,
     }
 
     Ok(())
@@ -1 +0,0 @@
-// use torut;
@@ -0,0 +1,110 @@
+//! # Peer authentication
+//! Storage clients and providers are identified by Onion v3 addresses, which are ed25519 public keys.
+//! Each side proves who it is by signing a challenge with the secret key behind its address.
+use std::{convert::TryFrom, fmt, net::SocketAddr, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, Signature};
+use torut::onion::{OnionAddressV3, TorSecretKeyV3};
+
+const CONNECTION_SCHEME: &str = "forage://";
+
+pub fn sign(key: &TorSecretKeyV3, message: &[u8]) -> Result<[u8; 64]> {
+    let expanded = ExpandedSecretKey::from_bytes(&key.as_bytes())?;
+    let public = PublicKey::from_bytes(key.public().as_bytes())?;
+    Ok(expanded.sign(message, &public).to_bytes())
+}
+
+pub fn verify(address: &OnionAddressV3, message: &[u8], signature: &[u8]) -> Result<()> {
+    let public = PublicKey::from_bytes(address.get_public_key().as_bytes())?;
+    let signature = Signature::try_from(signature)?;
+    public
+        .verify_strict(message, &signature)
+        .map_err(|_| anyhow!("Invalid signature from {}", address))
+}
+
+/// ## Connection string
+/// Issued by a storage provider to an authorized storage client, out-of-band.
+/// Contains the client's secret key, so it should be shared only with the client.
+///
+/// `forage://<client secret key hex>@<provider onion address>:<port>`, optionally followed by `?via=<host:port>`
+/// to connect directly instead of over Tor (for local networks and tests).
+pub struct ConnectionString {
+    pub client_key: TorSecretKeyV3,
+    pub provider: OnionAddressV3,
+    pub port: u16,
+    pub via: Option<SocketAddr>,
+}
+
+impl ConnectionString {
+    /// Generates a new client identity for a provider
+    pub fn generate(provider: OnionAddressV3, port: u16) -> Self {
+        Self {
+            client_key: TorSecretKeyV3::generate(),
+            provider,
+            port,
+            via: None,
+        }
+    }
+
+    pub fn client_address(&self) -> OnionAddressV3 {
+        self.client_key.public().get_onion_address()
+    }
+}
+
+impl fmt::Display for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}@{}:{}",
+            CONNECTION_SCHEME,
+            hex::encode(self.client_key.as_bytes()),
+            self.provider,
+            self.port
+        )?;
+
+        if let Some(via) = self.via {
+            write!(f, "?via={}", via)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ConnectionString {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "Invalid connection string. Expected {}<key>@<address>.onion:<port>",
+                CONNECTION_SCHEME
+            )
+        };
+
+        let rest = s
+            .trim()
+            .strip_prefix(CONNECTION_SCHEME)
+            .ok_or_else(invalid)?;
+        let (client_key, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        let (rest, via) = match rest.split_once("?via=") {
+            Some((rest, via)) => (rest, Some(via.parse()?)),
+            None => (rest, None),
+        };
+        let (provider, port) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        let provider = provider.strip_suffix(".onion").ok_or_else(invalid)?;
+
+        let key_bytes: [u8; 64] = hex::decode(client_key)?
+            .as_slice()
+            .try_into()
+            .map_err(|_| invalid())?;
+        ExpandedSecretKey::from_bytes(&key_bytes)?;
+
+        Ok(Self {
+            client_key: TorSecretKeyV3::from(key_bytes),
+            provider: provider.parse()?,
+            port: port.parse()?,
+            via,
+        })
+    }
+}
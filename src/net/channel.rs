@@ -0,0 +1,617 @@
+//! # Storage channels
+//! A storage client opens a channel to a storage provider using the connection string the provider issued it.
+//! Both sides prove they hold the keys behind their Onion v3 addresses before any data is exchanged.
+//! Messages are described in `net::protocol`.
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use rand::RngCore;
+use tokio::{
+    fs::File,
+    io::{sink, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use torut::onion::{OnionAddressV3, TorPublicKeyV3};
+
+use crate::{
+    backend::{local::LocalBackend, Staged, StorageBackend},
+    config::get_cfg,
+    db::{
+        contains_blob, get_channel, get_peer, insert_blob, release_client_storage, remove_blob,
+        reserve_client_storage, CapExceeded, PeerInfo,
+    },
+    hash::parse_blake3_hash,
+    net::{
+        auth::{sign, verify, ConnectionString},
+        protocol::{
+            negotiate, Message, CAPABILITIES, ERROR_CAP_EXCEEDED, ERROR_NOT_FOUND, ERROR_OTHER,
+            ERROR_UNAUTHORIZED, ERROR_UNSUPPORTED, ERROR_VERSION, MAX_DATA_LEN, PROTOCOL_VERSION,
+            SLICE_PROOFS,
+        },
+        tor,
+    },
+};
+
+const CLIENT_CONTEXT: &[u8] = b"Forage Storage Channel Client";
+const PROVIDER_CONTEXT: &[u8] = b"Forage Storage Channel Provider";
+const NONCE_LEN: usize = 32;
+
+/// Each side signs the other side's nonce and address, along with the version and capabilities it offered.
+/// Each signs under its own context, so signatures can't be replayed back.
+fn transcript(
+    context: &[u8],
+    nonce: &[u8; NONCE_LEN],
+    address: &OnionAddressV3,
+    (version, capabilities): (u16, u32),
+) -> Vec<u8> {
+    [
+        context,
+        nonce,
+        &address.get_raw_bytes(),
+        &version.to_be_bytes(),
+        &capabilities.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn nonce() -> [u8; NONCE_LEN] {
+    // TODO: replace all RNGs with CSPRNGs
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+async fn expect_ok<S: AsyncRead + Unpin>(stream: &mut S) -> Result<()> {
+    match Message::read(stream).await? {
+        Message::Ok => Ok(()),
+        message => Err(message.unexpected("Ok")),
+    }
+}
+
+async fn expect_size<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u64> {
+    match Message::read(stream).await? {
+        Message::Size { len } => Ok(len),
+        message => Err(message.unexpected("Size")),
+    }
+}
+
+/// Sends exactly `len` bytes from a reader as `Data` frames
+async fn send_data<R, W>(reader: &mut R, writer: &mut W, len: u64) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; MAX_DATA_LEN];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_DATA_LEN as u64) as usize;
+        reader.read_exact(&mut buf[..chunk]).await?;
+        Message::Data(buf[..chunk].to_vec()).write(writer).await?;
+        remaining -= chunk as u64;
+    }
+
+    Ok(())
+}
+
+/// Receives exactly `len` bytes of `Data` frames into a writer
+async fn receive_data<R, W>(reader: &mut R, writer: &mut W, len: u64) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut received = 0;
+
+    while received < len {
+        match Message::read(reader).await? {
+            Message::Data(data) if received + data.len() as u64 <= len => {
+                writer.write_all(&data).await?;
+                received += data.len() as u64;
+            }
+            Message::Data(_) => return Err(anyhow!("Received more data than expected")),
+            message => return Err(message.unexpected("Data")),
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// ## Client
+pub struct Channel<S> {
+    stream: S,
+    pub provider: OnionAddressV3,
+    pub version: u16,
+    pub capabilities: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Channel<S> {
+    /// Authenticates with the provider over an existing stream
+    pub async fn handshake(mut stream: S, connection: &ConnectionString) -> Result<Self> {
+        let offer = (PROTOCOL_VERSION, CAPABILITIES);
+        let client_nonce = nonce();
+
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+            public_key: connection.client_key.public().to_bytes(),
+            nonce: client_nonce,
+        }
+        .write(&mut stream)
+        .await?;
+
+        let (provider_offer, provider_key, provider_nonce) =
+            match Message::read(&mut stream).await? {
+                Message::Hello {
+                    version,
+                    capabilities,
+                    public_key,
+                    nonce,
+                } => ((version, capabilities), public_key, nonce),
+                message => return Err(message.unexpected("Hello")),
+            };
+
+        if provider_key != connection.provider.get_public_key().to_bytes() {
+            return Err(anyhow!(
+                "Storage provider's key doesn't match {}",
+                connection.provider
+            ));
+        }
+
+        let (version, capabilities) = negotiate(offer, provider_offer)?;
+
+        let signature = match Message::read(&mut stream).await? {
+            Message::Auth { signature } => signature,
+            message => return Err(message.unexpected("Auth")),
+        };
+
+        // The provider is checked first, so nothing is signed for an impostor
+        verify(
+            &connection.provider,
+            &transcript(
+                PROVIDER_CONTEXT,
+                &client_nonce,
+                &connection.client_address(),
+                provider_offer,
+            ),
+            &signature,
+        )?;
+
+        let signature = sign(
+            &connection.client_key,
+            &transcript(CLIENT_CONTEXT, &provider_nonce, &connection.provider, offer),
+        )?;
+        Message::Auth { signature }.write(&mut stream).await?;
+        expect_ok(&mut stream).await?;
+
+        Ok(Self {
+            stream,
+            provider: connection.provider,
+            version,
+            capabilities,
+        })
+    }
+
+    /// Stores an encoded file with the provider
+    pub async fn put(&mut self, blake3_hash: &str, encoded_file_path: &Path) -> Result<u64> {
+        let mut file = File::open(encoded_file_path).await?;
+        let len = file.metadata().await?.len();
+
+        Message::PutBlob {
+            hash: parse_blake3_hash(blake3_hash)?,
+            len,
+        }
+        .write(&mut self.stream)
+        .await?;
+
+        // The provider checks it has room before anything is sent
+        expect_ok(&mut self.stream).await?;
+        send_data(&mut file, &mut self.stream, len).await?;
+        expect_ok(&mut self.stream).await?;
+
+        Ok(len)
+    }
+
+    /// Writes `len` bytes of an encoded file from `offset` to `out`, or everything after `offset` if `len` is 0
+    pub async fn get_range(
+        &mut self,
+        blake3_hash: &str,
+        offset: u64,
+        len: u64,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        Message::GetRange {
+            hash: parse_blake3_hash(blake3_hash)?,
+            offset,
+            len,
+        }
+        .write(&mut self.stream)
+        .await?;
+
+        let len = expect_size(&mut self.stream).await?;
+        receive_data(&mut self.stream, out, len).await?;
+
+        Ok(len)
+    }
+
+    /// Length of an encoded file stored with the provider
+    pub async fn stat(&mut self, blake3_hash: &str) -> Result<u64> {
+        Message::Stat {
+            hash: parse_blake3_hash(blake3_hash)?,
+        }
+        .write(&mut self.stream)
+        .await?;
+
+        expect_size(&mut self.stream).await
+    }
+
+    /// Challenges the provider for a slice of an encoded file, to be checked with `verify_slice`
+    pub async fn slice_proof(&mut self, blake3_hash: &str, slice_index: u64) -> Result<Vec<u8>> {
+        if self.capabilities & SLICE_PROOFS == 0 {
+            return Err(anyhow!(
+                "Storage provider {} doesn't support slice proofs",
+                self.provider
+            ));
+        }
+
+        Message::SliceChallenge {
+            hash: parse_blake3_hash(blake3_hash)?,
+            slice_index,
+        }
+        .write(&mut self.stream)
+        .await?;
+
+        match Message::read(&mut self.stream).await? {
+            Message::SliceProof(slice) => Ok(slice),
+            message => Err(message.unexpected("SliceProof")),
+        }
+    }
+
+    pub async fn delete(&mut self, blake3_hash: &str) -> Result<()> {
+        Message::Delete {
+            hash: parse_blake3_hash(blake3_hash)?,
+        }
+        .write(&mut self.stream)
+        .await?;
+
+        expect_ok(&mut self.stream).await
+    }
+}
+
+/// Connects to the provider directly if the connection string says to, otherwise over Tor
+pub async fn connect(connection: &ConnectionString) -> Result<Channel<TcpStream>> {
+    let stream = match connection.via {
+        Some(via) => TcpStream::connect(via).await?,
+        None => {
+            let cfg = get_cfg().await?;
+            tor::connect(&cfg.tor, &connection.provider, connection.port).await?
+        }
+    };
+
+    Channel::handshake(stream, connection).await
+}
+
+/// Connects to the provider a channel was opened to, if one was
+pub async fn connect_opened() -> Result<Option<Channel<TcpStream>>> {
+    match get_channel()? {
+        Some(connection) => Ok(Some(connect(&connection.parse()?).await?)),
+        None => Ok(None),
+    }
+}
+
+/// ## Provider
+/// Authenticates a storage client, then serves its requests until it disconnects
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send>(mut stream: S) -> Result<()> {
+    let key = tor::onion_key()?;
+    let address = key.public().get_onion_address();
+    let offer = (PROTOCOL_VERSION, CAPABILITIES);
+
+    let hello = match Message::read(&mut stream).await {
+        Ok(hello) => hello,
+        // Connections that close without a word are just checking the daemon is up
+        Err(e) if is_disconnect(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let (client_offer, client_key, client_nonce) = match hello {
+        Message::Hello {
+            version,
+            capabilities,
+            public_key,
+            nonce,
+        } => ((version, capabilities), public_key, nonce),
+        message => return Err(message.unexpected("Hello")),
+    };
+    let client = TorPublicKeyV3::from_bytes(&client_key)?.get_onion_address();
+
+    let provider_nonce = nonce();
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+        public_key: key.public().to_bytes(),
+        nonce: provider_nonce,
+    }
+    .write(&mut stream)
+    .await?;
+
+    let (version, capabilities) = match negotiate(offer, client_offer) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            Message::error(ERROR_VERSION, &e.to_string())
+                .write(&mut stream)
+                .await?;
+            return Err(e);
+        }
+    };
+
+    let signature = sign(
+        &key,
+        &transcript(PROVIDER_CONTEXT, &client_nonce, &client, offer),
+    )?;
+    Message::Auth { signature }.write(&mut stream).await?;
+
+    let signature = match Message::read(&mut stream).await? {
+        Message::Auth { signature } => signature,
+        message => return Err(message.unexpected("Auth")),
+    };
+
+    let authenticated = verify(
+        &client,
+        &transcript(CLIENT_CONTEXT, &provider_nonce, &address, client_offer),
+        &signature,
+    );
+    let authorized = matches!(get_peer(&client.to_string()).await?, Some(peer) if peer.client);
+
+    if authenticated.is_err() || !authorized {
+        Message::error(ERROR_UNAUTHORIZED, "Not an authorized storage client")
+            .write(&mut stream)
+            .await?;
+        return Err(anyhow!("{} is not an authorized storage client", client));
+    }
+
+    Message::Ok.write(&mut stream).await?;
+    info!(
+        "Storage client {} connected (protocol version {}, capabilities {:#x})",
+        client, version, capabilities
+    );
+
+    let mut backend = LocalBackend::for_client(client);
+    let mut received = 0;
+    let mut sent = 0;
+
+    loop {
+        let request = match Message::read(&mut stream).await {
+            Ok(request) => request,
+            Err(e) if is_disconnect(&e) => break,
+            Err(e) => return Err(e),
+        };
+
+        match request {
+            Message::PutBlob { hash, len } => {
+                received += store(&mut stream, &mut backend, &client, &hash.to_hex(), len).await?
+            }
+            Message::GetRange { hash, offset, len } => {
+                sent += send(&mut stream, &mut backend, &hash.to_hex(), offset, len).await?
+            }
+            Message::Stat { hash } => {
+                let reply = match backend.stat(&hash.to_hex()).await {
+                    Ok(len) => Message::Size { len },
+                    Err(e) => Message::error(ERROR_NOT_FOUND, &e.to_string()),
+                };
+                reply.write(&mut stream).await?
+            }
+            Message::SliceChallenge { hash, slice_index } if capabilities & SLICE_PROOFS != 0 => {
+                prove(&mut stream, &mut backend, &hash.to_hex(), slice_index).await?
+            }
+            Message::Delete { hash } => {
+                let reply = match remove(&mut backend, &client, &hash.to_hex()).await {
+                    Ok(()) => Message::Ok,
+                    Err(e) => Message::error(ERROR_NOT_FOUND, &e.to_string()),
+                };
+                reply.write(&mut stream).await?
+            }
+            Message::Unknown(message_type) => {
+                Message::error(
+                    ERROR_UNSUPPORTED,
+                    &format!("Unsupported message type {:#x}", message_type),
+                )
+                .write(&mut stream)
+                .await?
+            }
+            _ => {
+                Message::error(ERROR_UNSUPPORTED, "Unexpected message")
+                    .write(&mut stream)
+                    .await?
+            }
+        }
+    }
+
+    let used = match get_peer(&client.to_string()).await? {
+        Some(PeerInfo {
+            bytes_used,
+            cap: Some(cap),
+            ..
+        }) => format!("{} bytes of a {} MB cap", bytes_used, cap),
+        Some(peer) => format!("{} bytes, no cap", peer.bytes_used),
+        None => "unknown".to_owned(),
+    };
+    info!(
+        "Storage client {} disconnected: received {} bytes, sent {} bytes, using {}",
+        client, received, sent, used
+    );
+
+    Ok(())
+}
+
+fn is_disconnect(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Counts the file against the client's cap, then stages it. The cap is given back if no volume has room for it.
+async fn reserve(
+    backend: &mut LocalBackend,
+    client: &str,
+    blake3_hash: &str,
+    len: u64,
+) -> Result<PathBuf> {
+    reserve_client_storage(client, len).await?;
+
+    match backend.stage(blake3_hash, len).await {
+        Ok(staged) => Ok(staged),
+        Err(e) => {
+            release_client_storage(client, len).await?;
+            Err(e)
+        }
+    }
+}
+
+async fn store<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    backend: &mut LocalBackend,
+    client: &OnionAddressV3,
+    blake3_hash: &str,
+    len: u64,
+) -> Result<u64> {
+    let client = client.to_string();
+
+    // Blobs are named by their hash, so one that's already stored is received without being stored or counted against the cap again
+    if contains_blob(&client, blake3_hash).await? {
+        Message::Ok.write(stream).await?;
+        receive_data(stream, &mut sink(), len).await?;
+        Message::Ok.write(stream).await?;
+        return Ok(len);
+    }
+
+    let reserved = match reserve(backend, &client, blake3_hash, len).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            let code = if e.is::<CapExceeded>() {
+                ERROR_CAP_EXCEEDED
+            } else {
+                ERROR_OTHER
+            };
+            Message::error(code, &e.to_string()).write(stream).await?;
+            return Ok(0);
+        }
+    };
+
+    // Received under a temporary name, which is removed unless the blob is stored
+    let staged = Staged::new(reserved.with_extension("partial"));
+    let stored = receive_blob(stream, backend, &client, blake3_hash, len, staged.path()).await;
+    backend.unstage(&reserved);
+
+    if let Err(e) = stored {
+        // Clients aren't charged for blobs that weren't stored, like when they disconnect partway through
+        release_client_storage(&client, len).await?;
+        return Err(e);
+    }
+    staged.stored();
+
+    debug!("Stored {} for {}", blake3_hash, client);
+    Message::Ok.write(stream).await?;
+
+    Ok(len)
+}
+
+/// Receives a blob where it was staged, then stores and records it
+async fn receive_blob<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    backend: &mut LocalBackend,
+    client: &str,
+    blake3_hash: &str,
+    len: u64,
+    staged: &Path,
+) -> Result<()> {
+    Message::Ok.write(stream).await?;
+
+    let mut file = File::create(staged).await?;
+    receive_data(stream, &mut file, len).await?;
+
+    if let Some(volume) = backend.put(blake3_hash, staged).await? {
+        if let Err(e) = insert_blob(client, blake3_hash, &volume, len).await {
+            let _ = backend.delete(blake3_hash).await;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn send<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    backend: &mut LocalBackend,
+    blake3_hash: &str,
+    offset: u64,
+    len: u64,
+) -> Result<u64> {
+    // Size is sent first, so the range is clamped to what's actually there
+    let available = match backend.stat(blake3_hash).await {
+        Ok(stored) => stored.saturating_sub(offset),
+        Err(e) => {
+            Message::error(ERROR_NOT_FOUND, &e.to_string())
+                .write(stream)
+                .await?;
+            return Ok(0);
+        }
+    };
+    let len = if len == 0 {
+        available
+    } else {
+        len.min(available)
+    };
+
+    Message::Size { len }.write(stream).await?;
+
+    let mut sent = 0;
+
+    while sent < len {
+        let chunk = (len - sent).min(MAX_DATA_LEN as u64);
+        let mut data = Vec::with_capacity(chunk as usize);
+        backend
+            .get_range(blake3_hash, offset + sent, chunk, &mut data)
+            .await?;
+
+        if data.len() as u64 != chunk {
+            return Err(anyhow!("{} changed while it was being sent", blake3_hash));
+        }
+
+        Message::Data(data).write(stream).await?;
+        sent += chunk;
+    }
+
+    Ok(sent)
+}
+
+/// Answers a slice challenge with the slice, so the client can check it against its bao hash
+async fn prove<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    backend: &mut LocalBackend,
+    blake3_hash: &str,
+    slice_index: u64,
+) -> Result<()> {
+    let reply = match backend.stat(blake3_hash).await {
+        Ok(_) => match backend.extract_slice(blake3_hash, slice_index).await {
+            Ok(slice) => Message::SliceProof(slice),
+            Err(e) => Message::error(ERROR_OTHER, &e.to_string()),
+        },
+        Err(e) => Message::error(ERROR_NOT_FOUND, &e.to_string()),
+    };
+
+    reply.write(stream).await
+}
+
+/// Deletes the file and gives its bytes back to the client's cap
+async fn remove(
+    backend: &mut LocalBackend,
+    client: &OnionAddressV3,
+    blake3_hash: &str,
+) -> Result<()> {
+    let len = backend.stat(blake3_hash).await?;
+    backend.delete(blake3_hash).await?;
+    remove_blob(&client.to_string(), blake3_hash).await?;
+    release_client_storage(&client.to_string(), len).await
+}
@@ -0,0 +1,4 @@
+pub mod auth;
+pub mod channel;
+pub mod protocol;
+pub mod tor;
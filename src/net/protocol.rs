@@ -0,0 +1,370 @@
+//! # Wire protocol
+//! Storage channels speak a versioned, length-prefixed binary protocol.
+//!
+//! ## Framing
+//! Every message is a frame: a big-endian u32 length, followed by that many bytes, the first of which is the message type.
+//! Integers are big-endian, hashes are raw 32 byte blake3 hashes, and strings are a u16 length followed by UTF-8.
+//! Frames are never longer than `MAX_FRAME_LEN`, so file contents are sent as a series of `Data` frames.
+//!
+//! Later versions only ever append fields to existing messages, or add new message types.
+//! Receivers ignore bytes left over at the end of a frame, and reply to message types they don't know with an `UNSUPPORTED` error.
+//!
+//! ## Messages
+//! | Type | Message        | Fields                                                       |
+//! |------|----------------|--------------------------------------------------------------|
+//! | 0x01 | Hello          | version u16, capabilities u32, public key [32], nonce [32]   |
+//! | 0x02 | Auth           | signature [64]                                               |
+//! | 0x03 | Ok             |                                                              |
+//! | 0x04 | Error          | code u16, message string                                     |
+//! | 0x10 | PutBlob        | hash, length u64                                             |
+//! | 0x11 | Data           | bytes, up to the end of the frame                            |
+//! | 0x12 | GetRange       | hash, offset u64, length u64 (0 for everything after offset) |
+//! | 0x13 | Size           | length u64                                                   |
+//! | 0x14 | SliceChallenge | hash, slice index u64                                        |
+//! | 0x15 | SliceProof     | bytes, up to the end of the frame                            |
+//! | 0x16 | Stat           | hash                                                         |
+//! | 0x17 | Delete         | hash                                                         |
+//!
+//! ## Handshake
+//! 1. The client sends `Hello` with its public key, and the provider replies with `Hello` with its own.
+//!    The channel uses the lower of the two versions, and only the capabilities both sides offered.
+//! 2. The provider sends `Auth`, signing the client's nonce and address along with its own version and capabilities.
+//!    The client checks this against the provider address in its connection string.
+//! 3. The client sends `Auth`, signing the provider's nonce and address along with its own version and capabilities.
+//!    The provider replies `Ok` if the client is authorized, or `Error` otherwise.
+//!
+//! ## Requests
+//! Once authenticated, the client sends requests one at a time, each of which gets a reply before the next is sent.
+//! - `PutBlob`: `Ok` once there's room, then the client sends exactly `length` bytes of `Data`, then `Ok` once they're stored
+//! - `GetRange`: `Size`, followed by exactly that many bytes of `Data`
+//! - `SliceChallenge`: `SliceProof` (requires the `SLICE_PROOFS` capability)
+//! - `Stat`: `Size`
+//! - `Delete`: `Ok`
+//!
+//! Any request can be answered with `Error` instead.
+use std::{convert::TryInto, fmt};
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// ## Capabilities
+/// Optional features, negotiated during the handshake
+pub const SLICE_PROOFS: u32 = 1 << 0;
+
+/// Capabilities this version offers
+pub const CAPABILITIES: u32 = SLICE_PROOFS;
+
+/// Largest chunk of file contents sent in a single `Data` frame
+pub const MAX_DATA_LEN: usize = 1024 * 1024;
+pub const MAX_FRAME_LEN: usize = MAX_DATA_LEN + 1;
+
+/// ## Error codes
+pub const ERROR_OTHER: u16 = 0;
+pub const ERROR_UNAUTHORIZED: u16 = 1;
+pub const ERROR_NOT_FOUND: u16 = 2;
+pub const ERROR_CAP_EXCEEDED: u16 = 3;
+pub const ERROR_UNSUPPORTED: u16 = 4;
+pub const ERROR_VERSION: u16 = 5;
+
+const HELLO: u8 = 0x01;
+const AUTH: u8 = 0x02;
+const OK: u8 = 0x03;
+const ERROR: u8 = 0x04;
+const PUT_BLOB: u8 = 0x10;
+const DATA: u8 = 0x11;
+const GET_RANGE: u8 = 0x12;
+const SIZE: u8 = 0x13;
+const SLICE_CHALLENGE: u8 = 0x14;
+const SLICE_PROOF: u8 = 0x15;
+const STAT: u8 = 0x16;
+const DELETE: u8 = 0x17;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Hello {
+        version: u16,
+        capabilities: u32,
+        public_key: [u8; 32],
+        nonce: [u8; 32],
+    },
+    Auth {
+        signature: [u8; 64],
+    },
+    Ok,
+    Error {
+        code: u16,
+        message: String,
+    },
+    PutBlob {
+        hash: blake3::Hash,
+        len: u64,
+    },
+    Data(Vec<u8>),
+    GetRange {
+        hash: blake3::Hash,
+        offset: u64,
+        len: u64,
+    },
+    Size {
+        len: u64,
+    },
+    SliceChallenge {
+        hash: blake3::Hash,
+        slice_index: u64,
+    },
+    SliceProof(Vec<u8>),
+    Stat {
+        hash: blake3::Hash,
+    },
+    Delete {
+        hash: blake3::Hash,
+    },
+    /// A message type from a later version, which is answered with an `UNSUPPORTED` error
+    Unknown(u8),
+}
+
+/// An `Error` message received from the other side
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Reads fields from the body of a frame
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow!("Truncated message"));
+        }
+
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn hash(&mut self) -> Result<blake3::Hash> {
+        Ok(blake3::Hash::from(self.array::<32>()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.0.to_vec();
+        self.0 = &[];
+        rest
+    }
+}
+
+impl Message {
+    pub fn error(code: u16, message: &str) -> Self {
+        Self::Error {
+            code,
+            message: message.to_owned(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+
+        match self {
+            Self::Hello {
+                version,
+                capabilities,
+                public_key,
+                nonce,
+            } => {
+                body.push(HELLO);
+                body.extend_from_slice(&version.to_be_bytes());
+                body.extend_from_slice(&capabilities.to_be_bytes());
+                body.extend_from_slice(public_key);
+                body.extend_from_slice(nonce);
+            }
+            Self::Auth { signature } => {
+                body.push(AUTH);
+                body.extend_from_slice(signature);
+            }
+            Self::Ok => body.push(OK),
+            Self::Error { code, message } => {
+                // Messages are truncated to fit their u16 length, on a character boundary
+                let mut end = message.len().min(u16::MAX as usize);
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                body.push(ERROR);
+                body.extend_from_slice(&code.to_be_bytes());
+                body.extend_from_slice(&(end as u16).to_be_bytes());
+                body.extend_from_slice(&message.as_bytes()[..end]);
+            }
+            Self::PutBlob { hash, len } => {
+                body.push(PUT_BLOB);
+                body.extend_from_slice(hash.as_bytes());
+                body.extend_from_slice(&len.to_be_bytes());
+            }
+            Self::Data(data) => {
+                body.push(DATA);
+                body.extend_from_slice(data);
+            }
+            Self::GetRange { hash, offset, len } => {
+                body.push(GET_RANGE);
+                body.extend_from_slice(hash.as_bytes());
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(&len.to_be_bytes());
+            }
+            Self::Size { len } => {
+                body.push(SIZE);
+                body.extend_from_slice(&len.to_be_bytes());
+            }
+            Self::SliceChallenge { hash, slice_index } => {
+                body.push(SLICE_CHALLENGE);
+                body.extend_from_slice(hash.as_bytes());
+                body.extend_from_slice(&slice_index.to_be_bytes());
+            }
+            Self::SliceProof(slice) => {
+                body.push(SLICE_PROOF);
+                body.extend_from_slice(slice);
+            }
+            Self::Stat { hash } => {
+                body.push(STAT);
+                body.extend_from_slice(hash.as_bytes());
+            }
+            Self::Delete { hash } => {
+                body.push(DELETE);
+                body.extend_from_slice(hash.as_bytes());
+            }
+            Self::Unknown(message_type) => body.push(*message_type),
+        }
+
+        body
+    }
+
+    fn decode(body: &[u8]) -> Result<Self> {
+        let mut fields = Fields(body);
+        let message_type = fields.array::<1>()?[0];
+
+        Ok(match message_type {
+            HELLO => Self::Hello {
+                version: fields.u16()?,
+                capabilities: fields.u32()?,
+                public_key: fields.array()?,
+                nonce: fields.array()?,
+            },
+            AUTH => Self::Auth {
+                signature: fields.array()?,
+            },
+            OK => Self::Ok,
+            ERROR => Self::Error {
+                code: fields.u16()?,
+                message: fields.string()?,
+            },
+            PUT_BLOB => Self::PutBlob {
+                hash: fields.hash()?,
+                len: fields.u64()?,
+            },
+            DATA => Self::Data(fields.rest()),
+            GET_RANGE => Self::GetRange {
+                hash: fields.hash()?,
+                offset: fields.u64()?,
+                len: fields.u64()?,
+            },
+            SIZE => Self::Size { len: fields.u64()? },
+            SLICE_CHALLENGE => Self::SliceChallenge {
+                hash: fields.hash()?,
+                slice_index: fields.u64()?,
+            },
+            SLICE_PROOF => Self::SliceProof(fields.rest()),
+            STAT => Self::Stat {
+                hash: fields.hash()?,
+            },
+            DELETE => Self::Delete {
+                hash: fields.hash()?,
+            },
+            _ => Self::Unknown(message_type),
+        })
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin + ?Sized>(&self, writer: &mut W) -> Result<()> {
+        let body = self.encode();
+
+        if body.len() > MAX_FRAME_LEN {
+            return Err(anyhow!("Message too long to send: {} bytes", body.len()));
+        }
+
+        writer.write_u32(body.len() as u32).await?;
+        writer.write_all(&body).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Self> {
+        let len = reader.read_u32().await? as usize;
+
+        if len > MAX_FRAME_LEN {
+            return Err(anyhow!("Message too long to receive: {} bytes", len));
+        }
+
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).await?;
+
+        Self::decode(&body)
+    }
+
+    /// Turns an `Error` message into an error, and anything other than what was expected into one too
+    pub fn unexpected(self, expected: &str) -> anyhow::Error {
+        match self {
+            Self::Error { code, message } => ProtocolError { code, message }.into(),
+            message => anyhow!("Expected {}, but received {:?}", expected, message),
+        }
+    }
+}
+
+/// Version and capabilities a channel uses, given what each side offered
+pub fn negotiate(ours: (u16, u32), theirs: (u16, u32)) -> Result<(u16, u32)> {
+    let version = ours.0.min(theirs.0);
+
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(ProtocolError {
+            code: ERROR_VERSION,
+            message: format!(
+                "Protocol version {} is no longer supported, {} or later is needed",
+                version, MIN_PROTOCOL_VERSION
+            ),
+        }
+        .into());
+    }
+
+    Ok((version, ours.1 & theirs.1))
+}
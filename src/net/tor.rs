@@ -0,0 +1,275 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::{ready, Ready},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use serde::{de::value::BorrowedStrDeserializer, Deserialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use torut::{
+    control::{AsyncEvent, AuthenticatedConn, ConnError, TorAuthData, UnauthenticatedConn},
+    onion::{OnionAddressV3, TorSecretKeyV3},
+};
+
+use crate::{
+    config::TorCfg,
+    db::{get_onion_key, insert_onion_key},
+};
+
+/// Asynchronous Tor events aren't subscribed to, so they're ignored
+type EventHandler = fn(AsyncEvent<'static>) -> Ready<Result<(), ConnError>>;
+
+/// ## Onion v3 key
+/// Generated once and persisted, so the node keeps the same address across restarts
+pub fn onion_key() -> Result<TorSecretKeyV3> {
+    match get_onion_key()? {
+        Some(key) => Ok(TorSecretKeyV3::from(key)),
+        None => {
+            let key = TorSecretKeyV3::generate();
+            insert_onion_key(&key.as_bytes())?;
+            Ok(key)
+        }
+    }
+}
+
+pub fn onion_address() -> Result<OnionAddressV3> {
+    Ok(onion_key()?.public().get_onion_address())
+}
+
+/// ## Tor control port connection
+pub struct TorControl<S> {
+    conn: AuthenticatedConn<S, EventHandler>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TorControl<S> {
+    /// Authenticates with a password if one is given, or otherwise whatever the control port offers (null or cookie auth)
+    pub async fn authenticate(stream: S, password: Option<&str>) -> Result<Self> {
+        let mut utc = UnauthenticatedConn::new(stream);
+        let info = utc.load_protocol_info().await?;
+        debug!("Tor version: {}", info.tor_version);
+
+        let auth = match password {
+            Some(password) => TorAuthData::HashedPassword(Cow::Owned(password.to_owned())),
+            None => info.make_auth_data()?.ok_or_else(|| {
+                anyhow!("Tor control port requires a password. Set control_password in the [tor] config.")
+            })?,
+        };
+
+        utc.authenticate(&auth).await?;
+        let mut conn: AuthenticatedConn<S, EventHandler> = utc.into_authenticated().await;
+        conn.set_async_event_handler(Some(|_| ready(Ok(()))));
+
+        Ok(Self { conn })
+    }
+
+    /// Forwards connections to a port on the key's onion address to a local address.
+    /// The service is removed by Tor when this control connection is closed.
+    pub async fn add_onion(
+        &mut self,
+        key: &TorSecretKeyV3,
+        port: u16,
+        local_address: SocketAddr,
+    ) -> Result<OnionAddressV3> {
+        self.conn
+            .add_onion_v3(
+                key,
+                false,
+                false,
+                false,
+                None,
+                &mut [(port, local_address)].iter(),
+            )
+            .await?;
+
+        Ok(key.public().get_onion_address())
+    }
+
+    pub async fn del_onion(&mut self, address: &OnionAddressV3) -> Result<()> {
+        self.conn
+            .del_onion(&address.get_address_without_dot_onion())
+            .await?;
+        Ok(())
+    }
+}
+
+/// ## Hidden service
+/// Published for as long as it's kept around
+pub struct OnionService<S> {
+    pub address: OnionAddressV3,
+    pub port: u16,
+    pub local_address: SocketAddr,
+    control: TorControl<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> OnionService<S> {
+    /// Publishes this node's hidden service over an existing control port connection
+    pub async fn publish_over(stream: S, cfg: &TorCfg) -> Result<Self> {
+        let mut control = TorControl::authenticate(stream, cfg.control_password.as_deref()).await?;
+        let local_address = SocketAddr::from(([127, 0, 0, 1], cfg.service_port));
+        let address = control
+            .add_onion(&onion_key()?, cfg.service_port, local_address)
+            .await?;
+
+        info!(
+            "Hidden service published at {}:{}",
+            address, cfg.service_port
+        );
+
+        Ok(Self {
+            address,
+            port: cfg.service_port,
+            local_address,
+            control,
+        })
+    }
+
+    pub async fn unpublish(mut self) -> Result<()> {
+        self.control.del_onion(&self.address).await
+    }
+}
+
+impl OnionService<TcpStream> {
+    /// Publishes this node's hidden service using the configured Tor control port
+    pub async fn publish(cfg: &TorCfg) -> Result<Self> {
+        let stream = TcpStream::connect(cfg.control_address).await.map_err(|e| {
+            anyhow!(
+                "Couldn't reach Tor control port at {}: {}",
+                cfg.control_address,
+                e
+            )
+        })?;
+
+        Self::publish_over(stream, cfg).await
+    }
+}
+
+/// ## SOCKS proxy
+/// Connects to a port on an onion address through Tor's SOCKS5 proxy, which doesn't need authentication
+pub async fn connect(cfg: &TorCfg, address: &OnionAddressV3, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(cfg.socks_address).await.map_err(|e| {
+        anyhow!(
+            "Couldn't reach Tor SOCKS proxy at {}: {}",
+            cfg.socks_address,
+            e
+        )
+    })?;
+
+    // Version 5, offering only the "no authentication" method
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        return Err(anyhow!("Tor SOCKS proxy requires authentication"));
+    }
+
+    // Connect command, with the onion address as a domain name
+    let host = address.to_string();
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow!(
+            "Tor couldn't connect to {}:{} (SOCKS error {})",
+            address,
+            port,
+            reply[1]
+        ));
+    }
+
+    // Bound address and port aren't needed, but still have to be read past
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(anyhow!("Invalid SOCKS reply from Tor")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    debug!("Connected to {}:{} over Tor", address, port);
+
+    Ok(stream)
+}
+
+/// ## Fake control port
+/// Speaks just enough of the Tor control protocol to publish hidden services, so tests can run offline.
+/// Accepts null authentication and records which services are published.
+#[derive(Clone, Default)]
+pub struct FakeControlPort {
+    onions: Arc<Mutex<HashMap<String, String>>>, // Service ID to port mapping
+}
+
+impl FakeControlPort {
+    /// Currently published onion addresses, without `.onion`
+    pub fn published(&self) -> HashMap<String, String> {
+        self.onions.lock().unwrap().clone()
+    }
+
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+
+            let mut words = line.trim_end().split(' ');
+            let reply = match words.next().unwrap_or_default() {
+                "PROTOCOLINFO" => "250-PROTOCOLINFO 1\r\n\
+                                   250-AUTH METHODS=NULL\r\n\
+                                   250-VERSION Tor=\"0.4.6.8\"\r\n\
+                                   250 OK\r\n"
+                    .to_owned(),
+                "AUTHENTICATE" => "250 OK\r\n".to_owned(),
+                "ADD_ONION" => {
+                    self.add_onion(words.next(), words.find(|w| w.starts_with("Port=")))?
+                }
+                "DEL_ONION" => {
+                    let service_id = words.next().unwrap_or_default();
+                    match self.onions.lock().unwrap().remove(service_id) {
+                        Some(_) => "250 OK\r\n".to_owned(),
+                        None => "552 Unknown Onion Service id\r\n".to_owned(),
+                    }
+                }
+                _ => "510 Unrecognized command\r\n".to_owned(),
+            };
+
+            stream.write_all(reply.as_bytes()).await?;
+        }
+    }
+
+    fn add_onion(&self, key: Option<&str>, port: Option<&str>) -> Result<String> {
+        let key = match key.and_then(|k| k.strip_prefix("ED25519-V3:")) {
+            Some(key) => key,
+            None => return Ok("512 Invalid key type\r\n".to_owned()),
+        };
+
+        let deserializer = BorrowedStrDeserializer::<serde::de::value::Error>::new(key);
+        let service_id = TorSecretKeyV3::deserialize(deserializer)?
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+
+        let port = port
+            .unwrap_or_default()
+            .trim_start_matches("Port=")
+            .to_owned();
+        let reply = format!("250-ServiceID={}\r\n250 OK\r\n", service_id);
+        self.onions.lock().unwrap().insert(service_id, port);
+
+        Ok(reply)
+    }
+}
@@ -0,0 +1,198 @@
+//! # Node status
+//! A summary of this node's volumes, files, verifications and peers, shown by `forage status`.
+use std::{fmt, path::PathBuf};
+
+use anyhow::Result;
+use human_bytes::human_bytes;
+use serde::Serialize;
+
+use crate::{config::get_cfg, daemon, db};
+
+#[derive(Serialize)]
+pub struct NodeStatus {
+    pub daemon_running: bool,
+    pub volumes: Vec<VolumeStatus>,
+    pub files: FileStatus,
+    pub last_verification: Option<VerificationStatus>,
+    pub track_records: Vec<TrackRecordStatus>,
+    pub peers: PeerStatus,
+}
+
+#[derive(Serialize)]
+pub struct VolumeStatus {
+    pub path: PathBuf,
+    pub allocated: u64, // Allocated capacity in bytes
+    pub used: u64,      // Encoded bytes stored, including files stored for storage clients
+}
+
+#[derive(Serialize)]
+pub struct FileStatus {
+    pub files: u64,
+    pub dropped: u64,
+    pub removed: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Serialize)]
+pub struct VerificationStatus {
+    pub path: PathBuf,
+    pub blake3_hash: String,
+    pub date: String, // RFC 3339
+    pub verified: bool,
+}
+
+/// How a storage provider, or this node's volumes if there's no peer, have held up to verification
+#[derive(Serialize)]
+pub struct TrackRecordStatus {
+    pub peer: Option<String>,
+    pub checks: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    pub last_passed: Option<String>, // RFC 3339
+    pub last_failed: Option<String>, // RFC 3339
+}
+
+#[derive(Serialize)]
+pub struct PeerStatus {
+    pub clients: u64,
+    pub providers: u64,
+}
+
+pub async fn get_status() -> Result<NodeStatus> {
+    let cfg = get_cfg().await?;
+    let usage = db::get_volume_usage().await?;
+
+    let volumes = cfg
+        .volumes
+        .iter()
+        .map(|vol| VolumeStatus {
+            path: vol.path.clone(),
+            allocated: vol.allocated_bytes(),
+            used: usage.get(&vol.path).copied().unwrap_or(0),
+        })
+        .collect();
+
+    let db::FileStats {
+        files,
+        dropped,
+        removed,
+        bytes_read,
+        bytes_written,
+    } = db::get_file_stats().await?;
+
+    let last_verification =
+        db::get_last_verification()
+            .await?
+            .map(|verification| VerificationStatus {
+                path: verification.path,
+                blake3_hash: verification.blake3_hash,
+                date: verification.date_verified.to_rfc3339(),
+                verified: verification.verified,
+            });
+
+    let track_records = db::get_track_records()
+        .await?
+        .into_iter()
+        .map(|record| TrackRecordStatus {
+            peer: record.peer,
+            checks: record.checks,
+            failures: record.failures,
+            consecutive_failures: record.consecutive_failures,
+            last_passed: record.last_passed.map(|date| date.to_rfc3339()),
+            last_failed: record.last_failed.map(|date| date.to_rfc3339()),
+        })
+        .collect();
+
+    let (clients, providers) = db::get_peer_counts().await?;
+
+    Ok(NodeStatus {
+        daemon_running: daemon::is_running(cfg.tor.service_port).await,
+        volumes,
+        files: FileStatus {
+            files,
+            dropped,
+            removed,
+            bytes_read,
+            bytes_written,
+        },
+        last_verification,
+        track_records,
+        peers: PeerStatus { clients, providers },
+    })
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Daemon:\t\t{}",
+            if self.daemon_running {
+                "running"
+            } else {
+                "stopped"
+            }
+        )?;
+
+        writeln!(f, "Volumes:")?;
+        for volume in &self.volumes {
+            let percent = if volume.allocated == 0 {
+                0.0
+            } else {
+                volume.used as f64 / volume.allocated as f64 * 100.0
+            };
+            writeln!(
+                f,
+                "\t{}\t{} of {} ({:.1}%)",
+                volume.path.to_string_lossy(),
+                human_bytes(volume.used as f64),
+                human_bytes(volume.allocated as f64),
+                percent
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Files:\t\t{} ({} dropped, {} removed)",
+            self.files.files, self.files.dropped, self.files.removed
+        )?;
+        writeln!(
+            f,
+            "Stored:\t\t{} read, {} written",
+            human_bytes(self.files.bytes_read as f64),
+            human_bytes(self.files.bytes_written as f64)
+        )?;
+
+        match &self.last_verification {
+            Some(verification) => writeln!(
+                f,
+                "Verified:\t{} at {} ({})",
+                verification.path.to_string_lossy(),
+                verification.date,
+                if verification.verified {
+                    "passed"
+                } else {
+                    "failed"
+                }
+            )?,
+            None => writeln!(f, "Verified:\tnever")?,
+        }
+
+        for record in &self.track_records {
+            writeln!(
+                f,
+                "\t{}: {} of {} slices failed, {} in a row",
+                record.peer.as_deref().unwrap_or("this node"),
+                record.failures,
+                record.checks,
+                record.consecutive_failures
+            )?;
+        }
+
+        write!(
+            f,
+            "Peers:\t\t{} clients, {} providers",
+            self.peers.clients, self.peers.providers
+        )
+    }
+}
@@ -11,8 +11,10 @@ const HASH_KEY: &str = "8036656ceb7d0d35306d7b7737a4d3e56b4ce18d1f02733effda0958
 #[serial]
 async fn hash() -> Result<()> {
     use forage::{
-        config::get_storage_path,
-        hash::{encode, extract, hash_file, verify, EncodedFileInfo},
+        backend::{local::LocalBackend, StorageBackend},
+        hash::{
+            encode, encoded_len, extract, extract_slice, hash_file, verify_slice, EncodedFileInfo,
+        },
     };
 
     let mut hash_key: [u8; 32] = Default::default();
@@ -25,14 +27,21 @@ async fn hash() -> Result<()> {
         "test file matches hardcoded blake3 hash"
     );
 
+    let mut local = LocalBackend::default();
+    let encoded_file_path = local
+        .stage(&blake3_hash, encoded_len(orig_path.metadata()?.len()))
+        .await?;
+
     let EncodedFileInfo {
         bao_hash,
         read,
         written,
-    } = encode(orig_path, &blake3_hash).await?;
+        ..
+    } = encode(orig_path, &blake3_hash, &encoded_file_path).await?;
+
+    let volume = local.put(&blake3_hash, &encoded_file_path).await?;
+    assert!(volume.is_some(), "encoded file is placed on a volume");
 
-    let storage_path = get_storage_path().await?;
-    let encoded_file_path = storage_path.join(blake3_hash.as_str());
     let bytes_on_disk = File::open(&encoded_file_path)?.metadata()?.size();
 
     assert_eq!(read, 81155, "bytes read from original file");
@@ -43,12 +52,22 @@ async fn hash() -> Result<()> {
     );
     assert_eq!(bao_hash.to_hex().as_str(), BAO_HASH, "bao hash must match");
 
-    verify(&bao_hash, &encoded_file_path, 5).await?;
+    let slice = extract_slice(&encoded_file_path, 5)?;
+    verify_slice(&bao_hash, &slice, 5)?;
 
     let out_path = Path::new("/tmp/forage.jpg");
-    extract(out_path, &bao_hash, &blake3_hash, read).await?;
+    extract(
+        out_path,
+        &encoded_file_path,
+        &bao_hash,
+        &blake3_hash,
+        read,
+        None,
+        false,
+    )
+    .await?;
 
-    let decoded_bytes_on_disk = File::open(&out_path)?.metadata()?.size();
+    let decoded_bytes_on_disk = File::open(out_path)?.metadata()?.size();
     assert_eq!(
         decoded_bytes_on_disk, 81155,
         "decoded file matches original length"
@@ -63,13 +82,1635 @@ async fn hash() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn slice_padding() -> Result<()> {
+    use forage::hash::{encode, encoded_len, extract, SLICE_LEN};
+
+    for (len, slices) in [(0, 1), (SLICE_LEN, 1), (SLICE_LEN + 1, 2)] {
+        let orig_path = format!("/tmp/forage_padding_{}.bin", len);
+        let encoded_path = format!("/tmp/forage_padding_{}.bao", len);
+        let out_path = format!("/tmp/forage_padding_{}.out", len);
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&orig_path, &contents)?;
+
+        let encoded = encode(Path::new(&orig_path), "", Path::new(&encoded_path)).await?;
+        assert_eq!(encoded.read, len, "bytes read from {} byte file", len);
+        assert_eq!(
+            encoded.written,
+            encoded_len(SLICE_LEN * slices),
+            "{} byte file is padded out to {} slices",
+            len,
+            slices
+        );
+        assert_eq!(
+            File::open(&encoded_path)?.metadata()?.size(),
+            encoded.written,
+            "actual file size must match computed size"
+        );
+
+        extract(
+            Path::new(&out_path),
+            Path::new(&encoded_path),
+            &encoded.bao_hash,
+            "",
+            len,
+            None,
+            false,
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read(&out_path)?,
+            contents,
+            "{} byte file round trips without padding",
+            len
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn compression() -> Result<()> {
+    use forage::{
+        config::CompressionCfg,
+        db::insert_dictionary,
+        hash::{compress_bound, encode_file, extract, train_dictionary, EncodedFileInfo},
+    };
+    use rand::RngCore;
+
+    async fn round_trip(
+        orig_path: &str,
+        contents: &[u8],
+        compression: &CompressionCfg,
+    ) -> Result<EncodedFileInfo> {
+        let encoded_path = format!("{}.bao", orig_path);
+        let out_path = format!("{}.out", orig_path);
+        std::fs::write(orig_path, contents)?;
+
+        let encoded = encode_file(
+            Path::new(orig_path),
+            Path::new(&encoded_path),
+            compression,
+            None,
+        )?;
+        extract(
+            Path::new(&out_path),
+            Path::new(&encoded_path),
+            &encoded.bao_hash,
+            "",
+            encoded.read,
+            encoded.compressed,
+            false,
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read(&out_path)?,
+            contents,
+            "{} round trips through compression",
+            orig_path
+        );
+
+        Ok(encoded)
+    }
+
+    let compression = CompressionCfg {
+        enabled: true,
+        ..CompressionCfg::default()
+    };
+
+    let text = "Forage is for Storage. ".repeat(4096).into_bytes();
+    let encoded = round_trip("/tmp/forage_compressible.txt", &text, &compression).await?;
+    let compressed = encoded.compressed.expect("text is compressed");
+    assert!(
+        compressed.len < text.len() as u64 / 10,
+        "repetitive text compresses well"
+    );
+
+    // Small, similar records are what dictionaries are for
+    let record = |i: usize| {
+        format!(
+            "{{\"id\": {}, \"name\": \"forager-{}\", \"volume\": \"/tmp/forage_data\", \"allocated\": {}}}\n",
+            i,
+            i * 7919 % 1000,
+            i % 13
+        )
+        .into_bytes()
+    };
+    let samples: Vec<Vec<u8>> = (0..2000).map(record).collect();
+    let (dictionary_id, dictionary) = train_dictionary(&samples, 4096)?;
+    insert_dictionary(dictionary_id, &dictionary)?;
+
+    let encoded = round_trip("/tmp/forage_dictionary.json", &record(4242), &compression).await?;
+    assert_eq!(
+        encoded.compressed.and_then(|c| c.dictionary_id),
+        Some(dictionary_id),
+        "trained dictionary is used"
+    );
+
+    // Room set aside for a compressed file covers contents that grow when they're compressed
+    let mut random = vec![0; 200_000];
+    rand::thread_rng().fill_bytes(&mut random);
+    let encoded = round_trip("/tmp/forage_incompressible.bin", &random, &compression).await?;
+    let compressed = encoded.compressed.expect("binary is compressed");
+    assert!(compressed.len > random.len() as u64);
+    assert!(compressed.len <= compress_bound(random.len() as u64));
+
+    let jpeg = std::fs::read("forage.jpg")?;
+    let encoded = round_trip("/tmp/forage_skipped.jpg", &jpeg, &compression).await?;
+    assert!(
+        encoded.compressed.is_none(),
+        "image/jpeg is in skip_mime_types, so it's stored as-is"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn single_pass_encode() -> Result<()> {
+    use forage::{
+        backend::{local::LocalBackend, StorageBackend},
+        hash::{encoded_len, hash_and_encode, infer_mime_type, HashedFileInfo},
+    };
+
+    let mut hash_key: [u8; 32] = Default::default();
+    hash_key.copy_from_slice(&hex::decode(HASH_KEY)?);
+
+    let orig_path = Path::new("forage.jpg");
+    let mut local = LocalBackend::default();
+    let staged = local
+        .stage(
+            "staging-single-pass",
+            encoded_len(orig_path.metadata()?.len()),
+        )
+        .await?;
+
+    let HashedFileInfo {
+        blake3_hash,
+        mime_type,
+        encoded,
+    } = hash_and_encode(orig_path, &hash_key, &staged).await?;
+
+    assert_eq!(blake3_hash.to_hex().as_str(), BLAKE3_HASH);
+    assert_eq!(mime_type, infer_mime_type(orig_path)?);
+    assert_eq!(encoded.read, 81155);
+    assert_eq!(encoded.bao_hash.to_hex().as_str(), BAO_HASH);
+
+    // The temporary blob is moved to its hash once it's put
+    local.put(BLAKE3_HASH, &staged).await?;
+    assert!(!staged.exists());
+    assert!(local.locate(BLAKE3_HASH).await?.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn encrypted_single_pass() -> Result<()> {
+    use forage::{
+        config::CompressionCfg,
+        hash::{extract, hash_and_encode_file, hash_file, infer_mime_type, FileEncryption},
+    };
+
+    let mut hash_key: [u8; 32] = Default::default();
+    hash_key.copy_from_slice(&hex::decode(HASH_KEY)?);
+
+    let compression = CompressionCfg {
+        enabled: true,
+        ..CompressionCfg::default()
+    };
+    let text_path = Path::new("/tmp/forage_encrypted.txt");
+    std::fs::write(text_path, "Forage is for Storage. ".repeat(4096))?;
+
+    // Whether or not its hash was already known, a file is hashed, sniffed, compressed and encrypted as it's read
+    for orig_path in [Path::new("forage.jpg"), text_path] {
+        let hashed = hash_and_encode_file(
+            orig_path,
+            &hash_key,
+            Path::new("/tmp/forage_encrypted.bao"),
+            &compression,
+            FileEncryption::ByHash,
+        )?;
+        let known = hash_and_encode_file(
+            orig_path,
+            &hash_key,
+            Path::new("/tmp/forage_encrypted_known.bao"),
+            &compression,
+            FileEncryption::ByKnownHash(hashed.blake3_hash),
+        )?;
+
+        assert_eq!(hashed.blake3_hash, hash_file(orig_path, &hash_key)?);
+        assert_eq!(hashed.mime_type, infer_mime_type(orig_path)?);
+        assert_eq!(known.blake3_hash, hashed.blake3_hash);
+
+        for (encoded_path, encoded) in [
+            ("/tmp/forage_encrypted.bao", hashed.encoded),
+            ("/tmp/forage_encrypted_known.bao", known.encoded),
+        ] {
+            assert!(encoded.encrypted);
+            extract(
+                Path::new("/tmp/forage_encrypted.out"),
+                Path::new(encoded_path),
+                &encoded.bao_hash,
+                hashed.blake3_hash.to_hex().as_str(),
+                encoded.read,
+                encoded.compressed,
+                true,
+            )
+            .await?;
+            assert_eq!(
+                std::fs::read("/tmp/forage_encrypted.out")?,
+                std::fs::read(orig_path)?,
+                "{} round trips through encryption",
+                orig_path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn scan_cache() -> Result<()> {
+    use forage::file::walk_dir;
+    use rand::RngCore;
+    use std::time::{Duration, SystemTime};
+
+    let dir = std::env::temp_dir().join(format!("forage-scan-{}", rand::thread_rng().next_u64()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("scanned.txt");
+
+    // Files modified just now aren't cached, since they could still change within the same mtime
+    let write = |contents: &str| -> Result<()> {
+        std::fs::write(&path, contents)?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        Ok(())
+    };
+
+    write("first")?;
+    let (files, stats) = walk_dir(&dir, "", false)?;
+    assert_eq!((stats.cached, stats.hashed), (0, 1));
+
+    let (cached_files, stats) = walk_dir(&dir, "", false)?;
+    assert_eq!(
+        (stats.cached, stats.hashed),
+        (1, 0),
+        "unchanged file isn't hashed again"
+    );
+    assert_eq!(cached_files, files);
+
+    let (_, stats) = walk_dir(&dir, "", true)?;
+    assert_eq!(
+        (stats.cached, stats.hashed),
+        (0, 1),
+        "rescan hashes every file"
+    );
+
+    write("second")?;
+    let (changed_files, stats) = walk_dir(&dir, "", false)?;
+    assert_eq!(
+        (stats.cached, stats.hashed),
+        (0, 1),
+        "changed file is hashed again"
+    );
+    assert_ne!(changed_files[&path], files[&path]);
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn upload_pipeline() -> Result<()> {
+    use forage::{
+        db::get_revisions_by_prefix,
+        file::{delete_file, upload_path},
+    };
+    use rand::RngCore;
+    use std::time::{Duration, SystemTime};
+
+    let run = rand::thread_rng().next_u64();
+    let data_dir = std::env::temp_dir().join(format!("forage-upload-{}", run));
+    let prefix = format!("uploaded-{}", run);
+    std::fs::create_dir_all(data_dir.join(&prefix))?;
+
+    for i in 0..20 {
+        let mut contents = vec![0; 1000 * (i + 1)];
+        rand::thread_rng().fill_bytes(&mut contents);
+        let path = data_dir.join(&prefix).join(format!("{:02}.bin", i));
+        std::fs::write(&path, contents)?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+    }
+    // Same contents as an earlier file, so it's only stored once
+    let copy = data_dir.join(&prefix).join("20.bin");
+    std::fs::copy(data_dir.join(&prefix).join("07.bin"), &copy)?;
+    File::options()
+        .write(true)
+        .open(&copy)?
+        .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+
+    let stats = upload_path("", &data_dir, false).await?;
+    assert_eq!((stats.cached, stats.hashed), (0, 21));
+
+    // Records are inserted in the order files were found, with adjacent slice ranges
+    let uploaded = get_revisions_by_prefix(&prefix).await?;
+    let names: Vec<String> = uploaded
+        .iter()
+        .map(|file| {
+            file.path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    let expected: Vec<String> = (0..20).map(|i| format!("{:02}.bin", i)).collect();
+    assert_eq!(names, expected, "duplicate contents aren't stored again");
+    for pair in uploaded.windows(2) {
+        assert_eq!(pair[0].max_slice, pair[1].min_slice);
+    }
+
+    let stats = upload_path("", &data_dir, false).await?;
+    assert_eq!(
+        (stats.cached, stats.hashed),
+        (21, 0),
+        "nothing is read again"
+    );
+
+    for file in uploaded {
+        delete_file(file.blake3_hash).await?;
+    }
+    std::fs::remove_dir_all(&data_dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn failed_batch() -> Result<()> {
+    use chrono::Utc;
+    use forage::{
+        db::{contains_hash, get_file, insert_file, mark_as_removed, FileInfo, USR_CONFIG},
+        file::{delete_file, upload_path},
+        hash::{hash_file, parse_bao_hash},
+    };
+    use rand::RngCore;
+    use std::time::{Duration, SystemTime};
+
+    let run = rand::thread_rng().next_u64();
+    let data_dir = std::env::temp_dir().join(format!("forage-failed-{}", run));
+    std::fs::create_dir_all(&data_dir)?;
+    let path = data_dir.join("failed.bin");
+    let mut contents = vec![0; 4000];
+    rand::thread_rng().fill_bytes(&mut contents);
+    std::fs::write(&path, contents)?;
+    File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+    let blake3_hash = hash_file(&path, &USR_CONFIG.hash_key)?;
+
+    // A row already recorded for the same contents makes the batch fail to be inserted
+    insert_file(FileInfo {
+        blake3_hash,
+        bao_hash: parse_bao_hash(BAO_HASH)?,
+        bytes_read: 1,
+        bytes_written: 1,
+        min_slice: 0,
+        max_slice: 0,
+        path: "failed.bin".into(),
+        parent_rev: None,
+        mime_type: "application/octet-stream".to_owned(),
+        date_created: Utc::now(),
+        date_modified: Utc::now(),
+        date_accessed: Utc::now(),
+        dropped: false,
+        removed: false,
+        volume: None,
+        compressed: None,
+        encrypted: false,
+        chunked: false,
+    })
+    .await?;
+
+    assert!(upload_path("", &data_dir, false).await.is_err());
+    assert!(
+        !contains_hash(blake3_hash.as_bytes())?,
+        "contents of a batch that wasn't recorded aren't taken for uploaded"
+    );
+
+    mark_as_removed(blake3_hash).await?;
+    let stats = upload_path("", &data_dir, false).await?;
+    assert_eq!(stats.hashed, 1, "file is uploaded again");
+    let stored = get_file(&blake3_hash).await?.unwrap();
+    assert!(!stored.removed && stored.volume.is_some(), "and stored");
+
+    delete_file(blake3_hash).await?;
+    std::fs::remove_dir_all(&data_dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn staged_reservations() -> Result<()> {
+    use forage::{
+        backend::{local::LocalBackend, StorageBackend},
+        config::get_cfg,
+        db::get_volume_usage,
+    };
+
+    let volume = get_cfg().await?.volumes.remove(0);
+    let used = get_volume_usage()
+        .await?
+        .get(&volume.path)
+        .copied()
+        .unwrap_or(0);
+    let free = volume.allocated_bytes().saturating_sub(used);
+    assert!(free > 0, "test volume has room left");
+
+    // Files staged at the same time each have room set aside, even though neither is recorded yet
+    let size = free / 2 + 1;
+    let mut local = LocalBackend::default();
+    let staged = local.stage("staging-reserved-a", size).await?;
+    assert!(
+        local.stage("staging-reserved-b", size).await.is_err(),
+        "a volume isn't overfilled by files staged at once"
+    );
+
+    local.unstage(&staged);
+    let staged = local.stage("staging-reserved-b", size).await?;
+
+    // Files stay on the volume they were staged on
+    std::fs::write(&staged, b"x")?;
+    let hash_hex = blake3::hash(b"staged_reservations").to_hex();
+    let stored_volume = local.put(&hash_hex, &staged).await?;
+    assert_eq!(stored_volume, Some(volume.path.clone()));
+    assert!(volume.path.join(hash_hex.as_str()).exists());
+    local.delete(&hash_hex).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn encode_off_runtime() -> Result<()> {
+    use forage::hash::{encode, encoded_len};
+    use rand::RngCore;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let path = std::env::temp_dir().join(format!("forage-large-{}", rand::thread_rng().next_u64()));
+    let mut contents = vec![0; 8 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut contents);
+    std::fs::write(&path, contents)?;
+    let encoded_path = path.with_extension("bao");
+
+    // Tests run on a single-threaded runtime, so the ticker only runs if encoding isn't blocking it
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticker = tokio::spawn({
+        let ticks = ticks.clone();
+        async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                ticks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let hash_hex = blake3::hash(path.to_string_lossy().as_bytes()).to_hex();
+    let encoded = encode(&path, &hash_hex, &encoded_path).await?;
+    ticker.abort();
+
+    assert_eq!(
+        std::fs::metadata(&encoded_path)?.len(),
+        encoded_len(encoded.read)
+    );
+    assert!(
+        ticks.load(Ordering::Relaxed) > 0,
+        "runtime stays responsive while encoding"
+    );
+
+    std::fs::remove_file(&path)?;
+    std::fs::remove_file(&encoded_path)?;
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn fresh_install() {
     use forage::{download, list_files, upload, verify};
 
-    upload("").await.expect("uploaded");
+    upload("", false).await.expect("uploaded");
     verify().await.expect("verified");
-    download("").await.expect("downloaded");
+    download("", None, None, false).await.expect("downloaded");
     list_files("", 0).await.expect("listed");
 }
+
+#[test]
+fn file_listing() -> Result<()> {
+    use chrono::Utc;
+    use forage::{
+        db::FileInfo,
+        file::list_entries,
+        hash::{parse_bao_hash, parse_blake3_hash},
+    };
+
+    let file = |path: &str, bytes_read: u64| -> Result<FileInfo> {
+        Ok(FileInfo {
+            blake3_hash: parse_blake3_hash(BLAKE3_HASH)?,
+            bao_hash: parse_bao_hash(BAO_HASH)?,
+            bytes_read,
+            bytes_written: bytes_read,
+            min_slice: 0,
+            max_slice: 0,
+            path: path.into(),
+            parent_rev: None,
+            mime_type: "text/plain".to_owned(),
+            date_created: Utc::now(),
+            date_modified: Utc::now(),
+            date_accessed: Utc::now(),
+            dropped: false,
+            removed: false,
+            volume: None,
+            compressed: None,
+            encrypted: false,
+            chunked: false,
+        })
+    };
+    let files = vec![
+        file("notes.txt", 1)?,
+        file("docs/a.txt", 10)?,
+        file("docs/b.txt", 20)?,
+        file("docs/old/c.txt", 30)?,
+        file("docsets/d.txt", 40)?,
+    ];
+    let rows = |prefix, depth| -> Vec<(String, u64, u64, bool)> {
+        list_entries(&files, prefix, depth)
+            .into_iter()
+            .map(|l| {
+                let is_dir = l.is_dir();
+                (
+                    l.path.to_string_lossy().into_owned(),
+                    l.bytes_read,
+                    l.files,
+                    is_dir,
+                )
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        rows("/", 1),
+        vec![
+            ("docs".to_owned(), 60, 3, true),
+            ("docsets".to_owned(), 40, 1, true),
+            ("notes.txt".to_owned(), 1, 1, false),
+        ],
+        "directories below the depth are collapsed"
+    );
+    assert_eq!(
+        rows("docs/", 1),
+        vec![
+            ("docs/a.txt".to_owned(), 10, 1, false),
+            ("docs/b.txt".to_owned(), 20, 1, false),
+            ("docs/old".to_owned(), 30, 1, true),
+        ],
+        "depth is counted from the prefix's directory"
+    );
+    assert_eq!(rows("docs", 0).len(), 4, "depth 0 lists recursively");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn revision_history() -> Result<()> {
+    use chrono::{Duration, TimeZone, Utc};
+    use forage::{
+        db::{insert_file, FileInfo},
+        file::history,
+        hash::parse_bao_hash,
+    };
+    use rand::RngCore;
+
+    let path = format!("history/{}.txt", rand::thread_rng().next_u64());
+    let modified = Utc.timestamp_millis(1_600_000_000_123);
+    let mut hashes = vec![];
+    let mut parent_rev = None;
+
+    // Removed, so they're never picked for verification
+    for revision in 0..3 {
+        let blake3_hash = blake3::hash(format!("{}{}", path, revision).as_bytes());
+        insert_file(FileInfo {
+            blake3_hash,
+            bao_hash: parse_bao_hash(BAO_HASH)?,
+            bytes_read: 1,
+            bytes_written: 1,
+            min_slice: 0,
+            max_slice: 0,
+            path: path.clone().into(),
+            parent_rev,
+            mime_type: "text/plain".to_owned(),
+            date_created: modified,
+            date_modified: modified + Duration::days(revision),
+            date_accessed: modified,
+            dropped: true,
+            removed: true,
+            volume: None,
+            compressed: None,
+            encrypted: false,
+            chunked: false,
+        })
+        .await?;
+        parent_rev = Some(blake3_hash);
+        hashes.push(blake3_hash);
+    }
+
+    let revisions = history(path.as_ref()).await?;
+    hashes.reverse();
+    assert_eq!(
+        revisions.iter().map(|r| r.blake3_hash).collect::<Vec<_>>(),
+        hashes,
+        "revisions are listed newest first"
+    );
+    assert_eq!(
+        revisions[2].date_modified, modified,
+        "dates are read back as they were stored"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn delete_file() -> Result<()> {
+    use chrono::Utc;
+    use forage::{
+        backend::{local::LocalBackend, StorageBackend},
+        db::{get_file, insert_file, mark_as_removed, FileInfo},
+        file::delete_file,
+        hash::parse_bao_hash,
+    };
+    use rand::RngCore;
+
+    let path = format!("deleted/{}.txt", rand::thread_rng().next_u64());
+    let blake3_hash = blake3::hash(path.as_bytes());
+    let hash_hex = blake3_hash.to_hex();
+
+    let mut local = LocalBackend::default();
+    let staged = local.stage(&hash_hex, 1).await?;
+    std::fs::write(&staged, b"x")?;
+    let volume = local.put(&hash_hex, &staged).await?;
+
+    let file = |volume| -> Result<FileInfo> {
+        Ok(FileInfo {
+            blake3_hash,
+            bao_hash: parse_bao_hash(BAO_HASH)?,
+            bytes_read: 1,
+            bytes_written: 1,
+            min_slice: 0,
+            max_slice: 0,
+            path: path.clone().into(),
+            parent_rev: None,
+            mime_type: "text/plain".to_owned(),
+            date_created: Utc::now(),
+            date_modified: Utc::now(),
+            date_accessed: Utc::now(),
+            dropped: false,
+            removed: false,
+            volume,
+            compressed: None,
+            encrypted: false,
+            chunked: false,
+        })
+    };
+    insert_file(file(volume)?).await?;
+
+    delete_file(blake3_hash).await?;
+    assert!(!staged.exists(), "encoded file is deleted");
+    let removed = get_file(&blake3_hash).await?.unwrap();
+    assert!(
+        removed.removed && removed.dropped,
+        "file is kept track of as removed"
+    );
+
+    insert_file(file(None)?).await?;
+    let stored = get_file(&blake3_hash).await?.unwrap();
+    assert!(!stored.removed, "removed contents can be uploaded again");
+    assert!(
+        insert_file(file(None)?).await.is_err(),
+        "stored contents aren't inserted twice"
+    );
+    mark_as_removed(blake3_hash).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn slice_sampling() -> Result<()> {
+    use chrono::Utc;
+    use forage::{
+        db::{get_next_slice, get_verification_sample, insert_file, mark_as_removed, FileInfo},
+        hash::parse_bao_hash,
+    };
+    use rand::RngCore;
+
+    let run = rand::thread_rng().next_u64();
+    let mut hashes = vec![];
+
+    for (name, slices) in [("small", 1), ("large", 64), ("last", 2)] {
+        let path = format!("sampled/{}/{}", run, name);
+        let min_slice = get_next_slice().await?;
+        insert_file(FileInfo {
+            blake3_hash: blake3::hash(path.as_bytes()),
+            bao_hash: parse_bao_hash(BAO_HASH)?,
+            bytes_read: slices * 1024,
+            bytes_written: slices * 1024,
+            min_slice,
+            max_slice: min_slice + slices,
+            path: path.clone().into(),
+            parent_rev: None,
+            mime_type: "text/plain".to_owned(),
+            date_created: Utc::now(),
+            date_modified: Utc::now(),
+            date_accessed: Utc::now(),
+            dropped: false,
+            removed: false,
+            volume: None,
+            compressed: None,
+            encrypted: false,
+            chunked: false,
+        })
+        .await?;
+        hashes.push(blake3::hash(path.as_bytes()));
+    }
+
+    // Removing a file leaves a gap in global slice indices, which is never drawn or allocated again
+    let removed = hashes[1];
+    mark_as_removed(removed).await?;
+    let next_slice = get_next_slice().await?;
+
+    for _ in 0..20 {
+        for sampled in get_verification_sample(10).await? {
+            assert_ne!(sampled.file.blake3_hash, removed);
+            let slices = sampled.file.max_slice - sampled.file.min_slice;
+            assert!(
+                sampled.slice_indices.iter().all(|&index| index < slices),
+                "slices are drawn as offsets into the file"
+            );
+        }
+    }
+
+    for hash in [hashes[0], hashes[2]] {
+        mark_as_removed(hash).await?;
+    }
+    assert_eq!(get_next_slice().await?, next_slice);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn verify_data() -> Result<()> {
+    use chrono::Utc;
+    use forage::{
+        backend::{local::LocalBackend, StorageBackend},
+        db::{get_track_records, get_verifications, insert_file, FileInfo},
+        file::{delete_file, verify_data},
+        hash::{encode, encoded_len, EncodedFileInfo},
+    };
+    use rand::RngCore;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let path = format!("verified/{}.jpg", rand::thread_rng().next_u64());
+    let blake3_hash = blake3::hash(path.as_bytes());
+    let hash_hex = blake3_hash.to_hex();
+
+    let orig_path = Path::new("forage.jpg");
+    let mut local = LocalBackend::default();
+    let staged = local
+        .stage(&hash_hex, encoded_len(orig_path.metadata()?.len()))
+        .await?;
+    let EncodedFileInfo {
+        bao_hash, stored, ..
+    } = encode(orig_path, &hash_hex, &staged).await?;
+    let volume = local.put(&hash_hex, &staged).await?;
+    let slices = stored.div_ceil(1024);
+
+    insert_file(FileInfo {
+        blake3_hash,
+        bao_hash,
+        bytes_read: stored,
+        bytes_written: stored,
+        min_slice: 0,
+        max_slice: slices,
+        path: path.into(),
+        parent_rev: None,
+        mime_type: "image/jpeg".to_owned(),
+        date_created: Utc::now(),
+        date_modified: Utc::now(),
+        date_accessed: Utc::now(),
+        dropped: false,
+        removed: false,
+        volume,
+        compressed: None,
+        encrypted: false,
+        chunked: false,
+    })
+    .await?;
+
+    // The newest file is always checked
+    let checked = |report: &forage::file::VerificationReport| -> Option<bool> {
+        report
+            .files
+            .iter()
+            .find(|file| file.blake3_hash == hash_hex.as_str())
+            .map(|file| file.passed())
+    };
+
+    let report = verify_data(None).await?;
+    assert_eq!(checked(&report), Some(true), "stored file is verified");
+    let file = report
+        .files
+        .iter()
+        .find(|file| file.blake3_hash == hash_hex.as_str())
+        .unwrap();
+    assert_eq!(file.slices.len(), 3, "several slices are checked per file");
+
+    let mut encoded = std::fs::OpenOptions::new().write(true).open(&staged)?;
+    for slice in 0..slices {
+        encoded.seek(SeekFrom::Start(slice * 1024 + 512))?;
+        encoded.write_all(b"corrupted")?;
+    }
+    drop(encoded);
+
+    let report = verify_data(None).await?;
+    assert_eq!(checked(&report), Some(false), "corrupted file fails");
+    assert!(!report.passed());
+
+    // The newest file is checked last, so its slices are the latest verifications of this node's volumes
+    let recorded: Vec<_> = get_verifications(6)
+        .await?
+        .into_iter()
+        .filter(|verification| verification.blake3_hash == hash_hex.as_str())
+        .collect();
+    assert_eq!(recorded.len(), 6, "every slice checked is recorded");
+    assert!(recorded[..3]
+        .iter()
+        .all(|v| !v.passed && v.error.is_some() && v.peer.is_none()));
+    assert!(recorded[3..].iter().all(|v| v.passed));
+
+    let local = get_track_records()
+        .await?
+        .into_iter()
+        .find(|record| record.peer.is_none())
+        .unwrap();
+    assert!(
+        local.consecutive_failures >= 3,
+        "failures since the last pass are counted"
+    );
+
+    delete_file(blake3_hash).await?;
+
+    Ok(())
+}
+
+#[test]
+fn retention() -> Result<()> {
+    use chrono::{Duration, TimeZone, Utc};
+    use forage::{config::RetentionCfg, db::FileInfo, hash::parse_bao_hash};
+
+    let now = Utc.ymd(2021, 10, 15).and_hms(12, 0, 0);
+    // Newest first: two revisions a day for the past 60 days, the newest of which is current
+    let revisions: Vec<FileInfo> = (0..120)
+        .map(|i| {
+            let path = format!("retention/{}", i);
+            Ok(FileInfo {
+                blake3_hash: blake3::hash(path.as_bytes()),
+                bao_hash: parse_bao_hash(BAO_HASH)?,
+                bytes_read: 1,
+                bytes_written: 1,
+                min_slice: 0,
+                max_slice: 0,
+                path: path.into(),
+                parent_rev: None,
+                mime_type: "text/plain".to_owned(),
+                date_created: now,
+                date_modified: now - Duration::hours(12 * i),
+                date_accessed: now,
+                dropped: i > 0,
+                removed: false,
+                volume: None,
+                compressed: None,
+                encrypted: false,
+                chunked: false,
+            })
+        })
+        .collect::<Result<_>>()?;
+    let kept =
+        |retention: RetentionCfg| revisions.len() - retention.to_prune(&revisions, now).len();
+
+    assert_eq!(kept(RetentionCfg::default()), 1, "current revision is kept");
+    assert_eq!(
+        kept(RetentionCfg {
+            keep_last: Some(5),
+            ..Default::default()
+        }),
+        5
+    );
+    assert_eq!(
+        kept(RetentionCfg {
+            keep_within_days: Some(3),
+            ..Default::default()
+        }),
+        7,
+        "revisions up to and including 3 days old are kept"
+    );
+    assert_eq!(
+        kept(RetentionCfg {
+            keep_daily: Some(7),
+            ..Default::default()
+        }),
+        7,
+        "one revision is kept each day"
+    );
+    assert_eq!(
+        kept(RetentionCfg {
+            keep_daily: Some(7),
+            keep_monthly: Some(3),
+            ..Default::default()
+        }),
+        9,
+        "the newest revision of this month is also today's"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn volume_placement() {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use forage::config::{select_volume, Placement, Volume, VolumeFull};
+
+    const MB: u64 = 1024 * 1024;
+
+    let volumes = vec![
+        Volume {
+            path: PathBuf::from("/tmp/forage_vol_a"),
+            allocated: 1,
+        },
+        Volume {
+            path: PathBuf::from("/tmp/forage_vol_b"),
+            allocated: 2,
+        },
+    ];
+
+    let mut usage = HashMap::new();
+    let chosen = select_volume(&volumes, Placement::FreeSpace, &usage, BLAKE3_HASH, 1024);
+    assert_eq!(
+        chosen.unwrap().path,
+        volumes[1].path,
+        "larger allocation is chosen"
+    );
+
+    usage.insert(volumes[1].path.clone(), 2 * MB);
+    let chosen = select_volume(&volumes, Placement::FreeSpace, &usage, BLAKE3_HASH, 1024);
+    assert_eq!(
+        chosen.unwrap().path,
+        volumes[0].path,
+        "volume with free space is chosen"
+    );
+
+    let sharded = select_volume(&volumes, Placement::HashShard, &usage, BLAKE3_HASH, 1024);
+    assert_eq!(
+        sharded.unwrap().path,
+        volumes[0].path,
+        "hash placement is redirected away from a full volume"
+    );
+
+    let VolumeFull { needed, available } =
+        select_volume(&volumes, Placement::HashShard, &usage, BLAKE3_HASH, 2 * MB).unwrap_err();
+    assert_eq!(needed, 2 * MB, "bytes needed are reported");
+    assert_eq!(available, MB, "largest free allocation is reported");
+}
+
+#[test]
+fn encryption() -> Result<()> {
+    use std::io::{Read, Write};
+
+    use chacha20poly1305::Key;
+    use forage::crypt::{encrypted_len, DecryptingReader, EncryptingWriter};
+
+    let key = Key::from_slice(&hex::decode(HASH_KEY)?).to_owned();
+    let plaintext = std::fs::read("forage.jpg")?;
+
+    // Encrypted sizes are known ahead of time, so volumes can be chosen before files are encrypted
+    for len in [0, 65536, 65537, plaintext.len()] {
+        let mut encryptor = EncryptingWriter::new(vec![], &key)?;
+        encryptor.write_all(&plaintext.repeat(2)[..len])?;
+        let ciphertext = encryptor.finish()?;
+        assert_eq!(ciphertext.len() as u64, encrypted_len(len as u64));
+    }
+
+    let mut encryptor = EncryptingWriter::new(vec![], &key)?;
+    encryptor.write_all(&plaintext)?;
+    let mut ciphertext = encryptor.finish()?;
+
+    // Trailing bytes, like slice padding, are ignored
+    ciphertext.extend_from_slice(&[0; 1024]);
+
+    let mut decrypted = vec![];
+    DecryptingReader::new(&ciphertext[..], &key)?.read_to_end(&mut decrypted)?;
+    assert_eq!(decrypted, plaintext, "decrypted file matches original file");
+
+    ciphertext[1000] ^= 1;
+    let mut tampered = vec![];
+    let result = DecryptingReader::new(&ciphertext[..], &key)?.read_to_end(&mut tampered);
+    assert!(result.is_err(), "tampered ciphertext fails to decrypt");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn onion_service() -> Result<()> {
+    use forage::{
+        config::TorCfg,
+        net::tor::{onion_address, FakeControlPort, OnionService},
+    };
+
+    let fake = FakeControlPort::default();
+    let (client, server) = tokio::io::duplex(4096);
+    let serving = fake.clone();
+    tokio::spawn(async move { serving.serve(server).await });
+
+    let cfg = TorCfg::default();
+    let service = OnionService::publish_over(client, &cfg).await?;
+    assert_eq!(
+        service.address,
+        onion_address()?,
+        "published address is the persisted one"
+    );
+
+    let published = fake.published();
+    let port = published.get(&service.address.get_address_without_dot_onion());
+    assert_eq!(
+        port.map(String::as_str),
+        Some(format!("{},127.0.0.1:{}", cfg.service_port, cfg.service_port).as_str()),
+        "service forwards to the local node"
+    );
+
+    service.unpublish().await?;
+    assert!(fake.published().is_empty(), "service is removed");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn new_client() -> Result<()> {
+    use forage::{
+        db::{get_peer, reserve_client_storage, CapExceeded},
+        net::auth::{sign, verify, ConnectionString},
+        new_client, onion_address,
+    };
+
+    let connection: ConnectionString = new_client("test client", Some(1)).await?.parse()?;
+    assert_eq!(
+        connection.provider.to_string(),
+        onion_address()?,
+        "connection string points to this node"
+    );
+
+    let client_address = connection.client_address();
+    let peer = get_peer(&client_address.to_string()).await?.unwrap();
+    assert!(peer.client && !peer.provider, "peer is a storage client");
+    assert_eq!(peer.cap, Some(1), "cap is persisted");
+
+    let signature = sign(&connection.client_key, b"challenge")?;
+    verify(&client_address, b"challenge", &signature)?;
+    assert!(verify(&client_address, b"other challenge", &signature).is_err());
+
+    reserve_client_storage(&peer.tor_v3, 1024 * 1024).await?;
+    let err = reserve_client_storage(&peer.tor_v3, 1).await.unwrap_err();
+    assert!(err.is::<CapExceeded>(), "client can't store past its cap");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn storage_channel() -> Result<()> {
+    use forage::{
+        backend::{remote::RemoteBackend, StorageBackend},
+        db::{get_peer, remove_channel},
+        hash::{encode, verify_slice, EncodedFileInfo},
+        net::{
+            auth::ConnectionString,
+            channel::{connect, serve},
+        },
+        new_client, onion_address, open_channel,
+    };
+    use tokio::net::TcpListener;
+
+    // This node is its own storage provider, listening locally instead of on a hidden service
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_address = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream));
+        }
+    });
+
+    let mut connection: ConnectionString = new_client("channel test", None).await?.parse()?;
+    connection.via = Some(local_address);
+
+    open_channel(&connection.to_string()).await?;
+    let provider = get_peer(&onion_address()?).await?.unwrap();
+    assert!(provider.provider, "provider is recorded");
+    remove_channel()?;
+
+    let mut remote = RemoteBackend::new(connect(&connection).await?);
+    let staged = remote.stage(BLAKE3_HASH, 0).await?;
+    std::fs::copy("forage.jpg", &staged)?;
+    remote.put(BLAKE3_HASH, &staged).await?;
+
+    let original = std::fs::read("forage.jpg")?;
+    assert_eq!(
+        remote.stat(BLAKE3_HASH).await?,
+        original.len() as u64,
+        "provider reports stored length"
+    );
+
+    let encoded = remote.fetch(BLAKE3_HASH).await?;
+    assert_eq!(
+        std::fs::read(&encoded.path)?,
+        original,
+        "file sent to provider is sent back"
+    );
+
+    let mut range = vec![];
+    remote.get_range(BLAKE3_HASH, 1000, 24, &mut range).await?;
+    assert_eq!(range, original[1000..1024], "ranges can be retrieved");
+
+    let client = get_peer(&connection.client_address().to_string())
+        .await?
+        .unwrap();
+    assert_eq!(client.bytes_used, original.len() as u64, "usage is counted");
+
+    remote.delete(BLAKE3_HASH).await?;
+    assert!(remote.stat(BLAKE3_HASH).await.is_err(), "file is deleted");
+    let client = get_peer(&connection.client_address().to_string())
+        .await?
+        .unwrap();
+    assert_eq!(client.bytes_used, 0, "deleted bytes are released");
+
+    // Slice challenges are answered by the provider, and checked against just the bao hash
+    assert_eq!(remote.provider(), connection.provider);
+    let staged = remote.stage(BLAKE3_HASH, 0).await?;
+    let EncodedFileInfo { bao_hash, .. } =
+        encode(Path::new("forage.jpg"), BLAKE3_HASH, &staged).await?;
+    remote.put(BLAKE3_HASH, &staged).await?;
+
+    let slice = remote.extract_slice(BLAKE3_HASH, 5).await?;
+    verify_slice(&bao_hash, &slice, 5)?;
+    assert!(
+        verify_slice(&bao_hash, &slice, 6).is_err(),
+        "slices are checked against where they were taken from"
+    );
+    remote.delete(BLAKE3_HASH).await?;
+
+    let mut unauthorized = ConnectionString::generate(connection.provider, 0);
+    unauthorized.via = Some(local_address);
+    assert!(
+        connect(&unauthorized).await.is_err(),
+        "clients without credentials are turned away"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn interrupted_put() -> Result<()> {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+
+    use forage::{
+        config::get_cfg,
+        db::get_peer,
+        net::{
+            auth::ConnectionString,
+            channel::{serve, Channel},
+        },
+        new_client,
+    };
+    use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+    /// Client end of a connection that drops once `left` more bytes have been written
+    struct Cutoff {
+        inner: DuplexStream,
+        left: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for Cutoff {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Cutoff {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let left = self.left.load(Ordering::SeqCst);
+            if left == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            let len = buf.len().min(left);
+            let written = std::task::ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+            self.left.fetch_sub(written, Ordering::SeqCst);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    let connection: ConnectionString = new_client("interrupted test", None).await?.parse()?;
+    let client = connection.client_address();
+    let bytes_used = || async {
+        Ok::<_, anyhow::Error>(get_peer(&client.to_string()).await?.unwrap().bytes_used)
+    };
+
+    let (client_end, provider_end) = duplex(1 << 20);
+    let provider = tokio::spawn(serve(provider_end));
+    let left = Arc::new(AtomicUsize::new(usize::MAX));
+    let mut channel = Channel::handshake(
+        Cutoff {
+            inner: client_end,
+            left: left.clone(),
+        },
+        &connection,
+    )
+    .await?;
+
+    // Client disconnects partway through sending the blob
+    left.store(4096, Ordering::SeqCst);
+    assert!(channel
+        .put(BLAKE3_HASH, Path::new("forage.jpg"))
+        .await
+        .is_err());
+    drop(channel);
+    assert!(provider.await?.is_err(), "provider sees the disconnect");
+
+    assert_eq!(bytes_used().await?, 0, "client isn't charged for it");
+    let dir = get_cfg().await?.volumes[0]
+        .path
+        .join(client.get_address_without_dot_onion());
+    assert!(!dir.join(BLAKE3_HASH).exists(), "nothing is stored");
+    assert!(
+        !dir.join(format!("{}.partial", BLAKE3_HASH)).exists(),
+        "what was received is removed"
+    );
+
+    // Putting the same blob twice only counts it once
+    let (client_end, provider_end) = duplex(1 << 20);
+    let provider = tokio::spawn(serve(provider_end));
+    let mut channel = Channel::handshake(client_end, &connection).await?;
+    let len = channel.put(BLAKE3_HASH, Path::new("forage.jpg")).await?;
+    channel.put(BLAKE3_HASH, Path::new("forage.jpg")).await?;
+    assert_eq!(bytes_used().await?, len);
+
+    channel.delete(BLAKE3_HASH).await?;
+    assert_eq!(bytes_used().await?, 0);
+    drop(channel);
+    provider.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn daemon() -> Result<()> {
+    use forage::{
+        backend::{remote::RemoteBackend, StorageBackend},
+        daemon::serve_until,
+        net::{auth::ConnectionString, channel::connect},
+        new_client,
+    };
+    use tokio::{net::TcpListener, sync::oneshot};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_address = listener.local_addr()?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let daemon = tokio::spawn(serve_until(listener, async {
+        let _ = stopped.await;
+    }));
+
+    let mut connection: ConnectionString = new_client("daemon test", None).await?.parse()?;
+    connection.via = Some(local_address);
+
+    let mut remote = RemoteBackend::new(connect(&connection).await?);
+    let staged = remote.stage(BLAKE3_HASH, 0).await?;
+    std::fs::copy("forage.jpg", &staged)?;
+    remote.put(BLAKE3_HASH, &staged).await?;
+    assert_eq!(
+        std::fs::read(&remote.fetch(BLAKE3_HASH).await?.path)?,
+        std::fs::read("forage.jpg")?,
+        "daemon serves stored files"
+    );
+    remote.delete(BLAKE3_HASH).await?;
+    drop(remote);
+
+    stop.send(()).unwrap();
+    daemon.await??;
+    assert!(
+        connect(&connection).await.is_err(),
+        "daemon stops accepting clients"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verification_schedule() {
+    use forage::{config::VerificationCfg, daemon::next_verification};
+    use std::time::Duration;
+
+    let cfg = VerificationCfg {
+        interval_minutes: 60,
+        jitter_minutes: 0,
+        max_backoff_minutes: 300,
+        ..Default::default()
+    };
+    let minutes = |failures| next_verification(&cfg, failures).as_secs() / 60;
+
+    assert_eq!(minutes(0), 60);
+    assert_eq!(minutes(2), 240, "interval doubles after each failure");
+    assert_eq!(minutes(3), 300, "backoff is capped");
+    assert_eq!(minutes(100), 300);
+
+    let jittered = next_verification(
+        &VerificationCfg {
+            jitter_minutes: 10,
+            ..cfg
+        },
+        0,
+    );
+    assert!(jittered >= Duration::from_secs(60 * 60) && jittered <= Duration::from_secs(70 * 60));
+}
+
+#[tokio::test]
+#[serial]
+async fn status() -> Result<()> {
+    use forage::{config::get_cfg, status::get_status};
+
+    let status = get_status().await?;
+    assert_eq!(
+        status.volumes.len(),
+        get_cfg().await?.volumes.len(),
+        "every volume is reported"
+    );
+    assert!(status.files.dropped <= status.files.files);
+
+    let json: serde_json::Value = serde_json::to_string(&status)?.parse()?;
+    assert_eq!(json["files"]["files"], status.files.files);
+    assert_eq!(json["daemon_running"], status.daemon_running);
+    assert!(status.to_string().contains("Volumes:"));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn wire_protocol() -> Result<()> {
+    use forage::{
+        net::{
+            auth::ConnectionString,
+            channel::{serve, Channel},
+            protocol::{
+                negotiate, Message, ProtocolError, ERROR_VERSION, MAX_DATA_LEN, SLICE_PROOFS,
+            },
+        },
+        new_client,
+    };
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    let hash = blake3::hash(b"forage");
+    let messages = vec![
+        Message::Hello {
+            version: 1,
+            capabilities: 0b101,
+            public_key: [1; 32],
+            nonce: [2; 32],
+        },
+        Message::Auth { signature: [3; 64] },
+        Message::Ok,
+        Message::error(2, "File not found"),
+        Message::PutBlob { hash, len: 86984 },
+        Message::Data(vec![4; MAX_DATA_LEN]),
+        Message::GetRange {
+            hash,
+            offset: 1024,
+            len: 0,
+        },
+        Message::Size { len: 86984 },
+        Message::SliceChallenge {
+            hash,
+            slice_index: 5,
+        },
+        Message::SliceProof(vec![5; 1024 + 64 * 7]),
+        Message::Stat { hash },
+        Message::Delete { hash },
+    ];
+
+    let (mut client, mut provider) = duplex(4096);
+    let sent = messages.clone();
+    tokio::spawn(async move {
+        for message in sent {
+            message.write(&mut client).await.unwrap();
+        }
+
+        // A message type from a later version, with fields appended to it
+        client.write_all(&[0, 0, 0, 3, 0x7f, 0, 0]).await.unwrap();
+        client.write_all(&[0, 0, 0, 10, 0x13]).await.unwrap();
+        client
+            .write_all(&[0, 0, 0, 0, 0, 0, 0, 9, 0xff])
+            .await
+            .unwrap();
+    });
+
+    for message in messages {
+        assert_eq!(Message::read(&mut provider).await?, message, "round trip");
+    }
+    assert_eq!(Message::read(&mut provider).await?, Message::Unknown(0x7f));
+    assert_eq!(
+        Message::read(&mut provider).await?,
+        Message::Size { len: 9 },
+        "appended fields are ignored"
+    );
+
+    assert_eq!(negotiate((2, 0b11), (1, 0b10))?, (1, 0b10));
+    let err = negotiate((1, 0), (0, 0)).unwrap_err();
+    assert_eq!(err.downcast::<ProtocolError>()?.code, ERROR_VERSION);
+
+    let connection: ConnectionString = new_client("protocol test", None).await?.parse()?;
+    let (client, provider) = duplex(4096);
+    tokio::spawn(serve(provider));
+    let channel = Channel::handshake(client, &connection).await?;
+    assert_eq!(channel.version, 1, "version is negotiated");
+    assert_eq!(
+        channel.capabilities, SLICE_PROOFS,
+        "capabilities are negotiated"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn content_defined_chunking() -> Result<()> {
+    use forage::{chunk::Chunker, config::ChunkingCfg};
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::collections::HashSet;
+
+    let cfg = ChunkingCfg {
+        enabled: true,
+        min_kib: 4,
+        avg_kib: 16,
+        max_kib: 64,
+    };
+    let mut data = vec![0; 2 * 1024 * 1024];
+    StdRng::seed_from_u64(25).fill_bytes(&mut data);
+
+    let chunks = Chunker::new(data.as_slice(), &cfg).collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(chunks.concat(), data, "chunks reassemble the file");
+    assert!(
+        chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| (4 * 1024..=64 * 1024).contains(&chunk.len())),
+        "chunks are between the smallest and largest size"
+    );
+
+    // Bytes inserted near the start only move the boundaries around them
+    let mut edited = data.clone();
+    edited.splice(1000..1000, [7; 100]);
+    let edited_chunks = Chunker::new(edited.as_slice(), &cfg).collect::<Result<Vec<_>, _>>()?;
+    let stored: HashSet<&Vec<u8>> = chunks.iter().collect();
+    let changed = edited_chunks
+        .iter()
+        .filter(|chunk| !stored.contains(chunk))
+        .count();
+
+    assert!(
+        changed <= 2,
+        "{} of {} chunks changed",
+        changed,
+        chunks.len()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn chunked_round_trip() -> Result<()> {
+    use forage::{
+        db::{ChunkInfo, USR_CONFIG},
+        hash::{extract_chunk, hash_and_encode_chunks, hash_file},
+    };
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::collections::HashMap;
+
+    let dir = std::env::temp_dir().join(format!("forage-chunked-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("image.bin");
+
+    // The same contents twice, so the second copy is cut into the same chunks once its first boundary is found
+    let mut half = vec![0; 4 * 1024 * 1024];
+    StdRng::seed_from_u64(25).fill_bytes(&mut half);
+    std::fs::write(&path, [half.as_slice(), half.as_slice()].concat())?;
+
+    let chunked = hash_and_encode_chunks(&path, &USR_CONFIG.hash_key, &dir.join("staging")).await?;
+    assert_eq!(chunked.blake3_hash, hash_file(&path, &USR_CONFIG.hash_key)?);
+    assert_eq!(chunked.bytes_read, 8 * 1024 * 1024);
+
+    let encoded = chunked.staged().count();
+    assert!(
+        encoded < chunked.chunks.len(),
+        "repeated chunks are encoded once"
+    );
+
+    let mut stored = HashMap::new();
+    for chunk in &chunked.chunks {
+        if let Some((staged, encoded)) = &chunk.encoded {
+            let info = ChunkInfo {
+                blake3_hash: chunk.blake3_hash,
+                bao_hash: encoded.bao_hash,
+                bytes_read: encoded.read,
+                bytes_written: encoded.written,
+                slices: chunk.slices,
+                volume: None,
+                compressed: encoded.compressed,
+                encrypted: encoded.encrypted,
+            };
+            stored.insert(chunk.blake3_hash, (staged.path().to_owned(), info));
+        }
+    }
+
+    // Written over an older, longer file, which the first chunk replaces
+    let out = dir.join("restored.bin");
+    std::fs::write(&out, vec![1; 9 * 1024 * 1024])?;
+
+    let mut offset = 0;
+    for chunk in &chunked.chunks {
+        let (staged, info) = &stored[&chunk.blake3_hash];
+        offset += extract_chunk(&out, offset, staged, info).await? as u64;
+    }
+
+    assert_eq!(offset, chunked.bytes_read);
+    assert!(
+        std::fs::read(&out)? == std::fs::read(&path)?,
+        "file is reassembled"
+    );
+
+    std::fs::remove_dir_all(dir)?;
+
+    Ok(())
+}